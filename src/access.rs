@@ -0,0 +1,91 @@
+//! `cron`-style allow and deny lists deciding whose table root may install in
+//! the system spool:
+//! - if the allow file exists, only the users listed there may;
+//! - otherwise, if the deny file exists, everyone but the users listed there may;
+//! - otherwise everyone may.
+//!
+//! Root is always permitted. Both files list one user name per line, blank
+//! lines and `#` comments are ignored.
+
+use std::{fs, io, path::Path};
+
+use crate::{config::Config, user::Credentials};
+
+fn read_list(path: &Path) -> io::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(list) => Ok(Some(list)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+fn is_listed(list: &str, name: &str) -> bool {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .any(|line| line == name)
+}
+
+/// Whether `user` may have a table; fails if a list exists but can't be read
+pub fn is_permitted(config: &Config, user: &Credentials) -> io::Result<bool> {
+    if user.uid.is_root() {
+        return Ok(true);
+    }
+
+    if let Some(allow) = read_list(&config.allow_file)? {
+        return Ok(is_listed(&allow, &user.name));
+    }
+
+    if let Some(deny) = read_list(&config.deny_file)? {
+        return Ok(!is_listed(&deny, &user.name));
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use nix::unistd::{Gid, Uid};
+    use uuid::Uuid;
+
+    use crate::{access::is_permitted, config::Config, user::Credentials};
+
+    fn user(name: &str, uid: u32) -> Credentials {
+        Credentials {
+            name: String::from(name),
+            uid: Uid::from_raw(uid),
+            gid: Gid::from_raw(uid),
+            groups: Vec::new(),
+            home: PathBuf::from("/"),
+        }
+    }
+
+    #[test]
+    fn test_allow_deny() {
+        let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir(&directory).unwrap();
+        let config = Config {
+            allow_file: directory.join("allow"),
+            deny_file: directory.join("deny"),
+            ..Config::default()
+        };
+        let (alice, bob, root) = (user("alice", 1000), user("bob", 1001), user("root", 0));
+
+        // No lists: everyone
+        assert!(is_permitted(&config, &alice).unwrap());
+
+        fs::write(&config.deny_file, "# nope\nbob\n").unwrap();
+        assert!(is_permitted(&config, &alice).unwrap());
+        assert!(!is_permitted(&config, &bob).unwrap());
+
+        // The allow list takes precedence over the deny one
+        fs::write(&config.allow_file, "  bob  \n\n").unwrap();
+        assert!(!is_permitted(&config, &alice).unwrap());
+        assert!(is_permitted(&config, &bob).unwrap());
+        assert!(is_permitted(&config, &root).unwrap());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -3,22 +3,34 @@ use figment::{
     providers::{Format, Toml},
     Figment,
 };
-use futures::StreamExt;
+use futures::{future::join_all, StreamExt};
 use inotify::{Event, EventMask, Inotify};
+use nix::unistd::Uid;
 use rsincronlib::{
     config::Config,
-    state::{ArcShared, Shared, State},
-    with_logging, SocketMessage, XDG,
+    debounce::{BurstKey, Debouncer},
+    limit::Limiter,
+    rename::{rename_events, Move, Renames},
+    socket::{setup_socket, DaemonStatus, ProtocolError, Reply, Request, Response, SocketRequest},
+    state::{ArcShared, Shared, State, Watch},
+    watch::{Execution, Outcome, Timeout, Trigger},
+    with_logging, SOCKET, XDG,
 };
 
-use tokio::sync::mpsc;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc::UnboundedReceiver,
+    task::JoinSet,
+};
 
 use std::{
     ffi::OsString,
-    path::PathBuf,
-    process::ExitCode,
+    fs,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::{self, ExitCode},
     sync::{Arc, OnceLock},
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
 use tracing::{event, Level};
@@ -38,10 +50,227 @@ struct Args {
             .expect("failed to get `rsincron.toml`: do I have permissions?")
         )]
     config: PathBuf,
+
+    /// Serve the system and per-user tables instead of the caller's own
+    #[arg(long)]
+    system: bool,
+}
+
+/// Runs the command of `watch` for `event`, read at `time` and standing for
+/// `count` coalesced events, unless its entry is paused, or running already
+/// without being loopable. Waits for the concurrency limits first
+async fn run_watch(
+    watch: &Watch,
+    event: &Event<OsString>,
+    renamed_from: Option<&Path>,
+    time: SystemTime,
+    count: u64,
+    state: &ArcShared,
+) {
+    if state.is_paused(&watch.key.root) {
+        event!(Level::DEBUG, path = ?watch.data.path, "watch paused: not executing");
+        return;
+    }
+
+    // Held until the command exits so that the events it causes are ignored.
+    // Debounced events were checked on arrival: a burst ending while another
+    // one's command runs was no doing of that command
+    let Some(_running) = state.start_run(watch) else {
+        event!(Level::DEBUG, path = ?watch.data.path, "command running: ignoring event");
+        return;
+    };
+
+    let attributes = &watch.data.attributes;
+    let Some(permit) = state
+        .limiter
+        .acquire(
+            &watch.key,
+            attributes.concurrency,
+            attributes.overflow,
+            count,
+        )
+        .await
+    else {
+        return;
+    };
+
+    state.record_fired(&event.wd, &watch.key);
+
+    let config = CONFIG.get().unwrap();
+    let timeout = Some(attributes.timeout.unwrap_or(config.command_timeout))
+        .filter(|after| !after.is_zero())
+        .map(|after| Timeout {
+            after,
+            grace: config.kill_grace_period,
+        });
+
+    let trigger = Trigger {
+        path: &watch.data.path,
+        root: &watch.key.root,
+        event,
+        renamed_from,
+        count: permit.count,
+        time,
+        line_number: watch.line_number,
+    };
+    let execution = Execution {
+        user: watch.user.as_ref(),
+        environment: attributes.environment,
+        timeout,
+        output: attributes.output.as_ref().unwrap_or(&config.output),
+        output_limits: config.output_limits(),
+        shell: attributes.shell.unwrap_or(config.shell),
+    };
+
+    let command = &watch.data.command;
+    let outcome = command.execute(&trigger, &execution).await;
+
+    match outcome {
+        Ok(Outcome {
+            status,
+            duration,
+            timed_out: true,
+        }) => event!(
+            Level::WARN,
+            command = command.program,
+            ?duration,
+            ?status,
+            "command timed out: killed its process group"
+        ),
+        Ok(Outcome {
+            status, duration, ..
+        }) => match status.code() {
+            Some(code) => event!(
+                Level::INFO,
+                command = command.program,
+                ?duration,
+                code,
+                "command exited"
+            ),
+            None => event!(
+                Level::WARN,
+                command = command.program,
+                ?duration,
+                signal = status.signal(),
+                "command killed by a signal"
+            ),
+        },
+        Err(error) => event!(
+            Level::ERROR,
+            ?error,
+            command = command.program,
+            line = %command.line,
+            user = watch.user.as_ref().map(|user| &user.name),
+            "failed to execute command"
+        ),
+    }
+}
+
+/// Runs the command of `watch` right away, or once its debounce window
+/// passes without another event
+async fn debounce_watch(
+    watch: &Watch,
+    event: &Event<OsString>,
+    renamed_from: Option<&Path>,
+    time: SystemTime,
+    state: &ArcShared,
+) {
+    let Some(window) = watch.data.attributes.debounce else {
+        return run_watch(watch, event, renamed_from, time, 1, state).await;
+    };
+
+    if event.mask == EventMask::IGNORED {
+        return run_watch(watch, event, renamed_from, time, 1, state).await;
+    }
+
+    // Checked now too, or the events the command causes would run it again
+    // once the window passes
+    if !watch.data.attributes.loopable && state.is_running(&watch.key) {
+        event!(Level::DEBUG, path = ?watch.data.path, "command running: ignoring event");
+        return;
+    }
+
+    let key = BurstKey::new(watch, event);
+    let burst = state
+        .debouncer
+        .coalesce(
+            key,
+            watch.clone(),
+            event.clone(),
+            renamed_from.map(Path::to_owned),
+            time,
+            window,
+        )
+        .await;
+
+    if let Some(burst) = burst {
+        event!(
+            Level::DEBUG,
+            path = ?burst.watch.data.path,
+            mask = ?burst.event.mask,
+            count = burst.count,
+            "burst over"
+        );
+        let renamed_from = burst.renamed_from.as_deref();
+        run_watch(
+            &burst.watch,
+            &burst.event,
+            renamed_from,
+            burst.time,
+            burst.count,
+            state,
+        )
+        .await;
+    }
+}
+
+/// Runs the commands of the `watches` which want `event`
+async fn run_watches(
+    watches: &[Watch],
+    event: &Event<OsString>,
+    renamed_from: Option<&Path>,
+    time: SystemTime,
+    state: &ArcShared,
+) {
+    join_all(
+        watches
+            .iter()
+            .filter(|watch| watch.wants(event))
+            .map(|watch| debounce_watch(watch, event, renamed_from, time, state)),
+    )
+    .await;
+}
+
+/// Runs commands once for a rename
+async fn run_rename(
+    from: Event<OsString>,
+    to: Event<OsString>,
+    time: SystemTime,
+    state: &ArcShared,
+) {
+    let (sources, destinations) = (state.get_watches(&from.wd), state.get_watches(&to.wd));
+    let renamed_from = match (sources.first(), &from.name) {
+        (Some(source), Some(name)) => Some(source.data.path.join(name)),
+        _ => None,
+    };
+    event!(
+        Level::DEBUG,
+        ?renamed_from,
+        name = ?to.name,
+        "paired rename"
+    );
+
+    let events = rename_events(&sources, &destinations, &from, &to);
+    join_all(events.iter().map(|(watch, event, renamed)| {
+        let renamed_from = renamed_from.as_deref().filter(|_| *renamed);
+        debounce_watch(watch, event, renamed_from, time, state)
+    }))
+    .await;
 }
 
 #[tracing::instrument(skip_all)]
 async fn handle_event(event: Event<OsString>, state: ArcShared) {
+    let time = SystemTime::now();
     event!(
         Level::INFO,
         event_id = event.wd.get_watch_descriptor_id(),
@@ -49,44 +278,84 @@ async fn handle_event(event: Event<OsString>, state: ArcShared) {
         name = ?event.name
     );
 
-    if state.has_socket() {
-        match state.rx_try_recv() {
-            Ok(SocketMessage::UpdateWatches) => state.reload_watches(),
-            Err(mpsc::error::TryRecvError::Empty) => (),
-            Err(error) => {
-                event!(Level::WARN, ?error);
-                state.unset_socket();
-            }
-        };
+    if state.is_table_event(&event) {
+        state.reload_watches();
     }
 
-    let Some(watch) = state.get_watch(&event.wd) else {
+    let watches = state.get_watches(&event.wd);
+    if watches.is_empty() {
+        // A table directory gone, watched again once back
+        if event.mask == EventMask::IGNORED {
+            state.fail_watches(&event.wd);
+        }
         return;
-    };
+    }
 
-    if let Err(error) = watch.command.execute(&watch.path, &event).await {
-        event!(
-            Level::ERROR,
-            ?error,
-            command = watch.command.program,
-            argv = ?watch.command.argv,
-            "failed to execute command"
-        );
+    // Before running anything, as debounced commands wait for their window
+    if watches.iter().any(|watch| watch.data.attributes.recursive)
+        && event.mask.contains(EventMask::ISDIR)
+        && event
+            .mask
+            .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+    {
+        if let Some(ref name) = event.name {
+            state.add_subdirectory(&event.wd, name);
+        }
+    }
+
+    let window = CONFIG.get().unwrap().rename_window;
+    let is_move = event
+        .mask
+        .intersects(EventMask::MOVED_FROM | EventMask::MOVED_TO);
+    if is_move && event.cookie != 0 && !window.is_zero() {
+        match state.renames.pair(event, window).await {
+            Some(Move::Rename { from, to }) => run_rename(from, to, time, &state).await,
+            Some(Move::Unpaired(event)) => run_watches(&watches, &event, None, time, &state).await,
+            // Run by the handler of the other half
+            None => (),
+        }
         return;
     }
 
-    if event.mask == EventMask::IGNORED {
-        let Some(watch) = state.remove_watch(&event.wd) else {
-            return;
-        };
+    run_watches(&watches, &event, None, time, &state).await;
 
-        event!(Level::WARN, ?event.mask, path = ?watch.path, "removing watch");
-        state.push_failed_watch(watch);
-        return;
+    if event.mask == EventMask::IGNORED {
+        for watch in state.fail_watches(&event.wd) {
+            event!(Level::WARN, ?event.mask, path = ?watch.data.path, "removing watch");
+        }
     }
+}
 
-    if watch.attributes.recursive && event.mask.contains(EventMask::ISDIR) {
-        state.reload_watches()
+#[tracing::instrument(skip(state, started))]
+fn handle_request(request: Request, state: &ArcShared, started: Instant) -> Reply {
+    match request {
+        Request::Ping => Ok(Response::Pong),
+        Request::Reload => {
+            state.reload_watches();
+            Ok(Response::Reloaded)
+        }
+        Request::ListWatches => Ok(Response::Watches(state.list_watches())),
+        Request::ListFailedWatches => Ok(Response::Watches(state.list_failed_watches())),
+        Request::Status => {
+            let (watches, failed_watches) = state.count_watches();
+            Ok(Response::Status(DaemonStatus {
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                pid: process::id(),
+                uptime: started.elapsed(),
+                tables: state.tables(),
+                watches,
+                failed_watches,
+                running_commands: state.limiter.running(),
+            }))
+        }
+        Request::Pause(root) => match state.pause(&root) {
+            true => Ok(Response::Paused),
+            false => Err(ProtocolError::UnknownWatch(root)),
+        },
+        Request::Resume(root) => match state.resume(&root) {
+            true => Ok(Response::Resumed),
+            false => Err(ProtocolError::UnknownWatch(root)),
+        },
     }
 }
 
@@ -97,37 +366,56 @@ async fn main() -> ExitCode {
 
     let args = Args::parse();
 
-    CONFIG
-        .set(
-            match Figment::new().join(Toml::file(args.config)).extract() {
-                Ok(c) => c,
-                Err(error) => {
-                    event!(
-                        Level::WARN,
-                        error = ?error.kind,
-                        "failed to parse configuration file. Using default configuration"
-                    );
-                    Config::default()
-                }
-            },
-        )
-        .unwrap();
+    let mut config: Config = match Figment::new().join(Toml::file(args.config)).extract() {
+        Ok(c) => c,
+        Err(error) => {
+            event!(
+                Level::WARN,
+                error = ?error.kind,
+                "failed to parse configuration file. Using default configuration"
+            );
+            Config::default()
+        }
+    };
+    config.system |= args.system;
+
+    if config.system && !Uid::effective().is_root() {
+        event!(
+            Level::WARN,
+            "not running as root: commands from user tables will fail to switch user"
+        );
+    }
+
+    CONFIG.set(config).unwrap();
 
     let Ok(mut inotify) = Inotify::init() else {
         event!(Level::ERROR, "failed to set up inotify instance");
         return ExitCode::FAILURE;
     };
 
+    let config = CONFIG.get().unwrap();
     let state = Arc::new(Shared {
         state: {
-            let mut state = State::new(&mut inotify, CONFIG.get().unwrap().to_owned());
+            let mut state = State::new(&mut inotify, config.to_owned());
             state.reload_watches();
             state.into()
         },
+        debouncer: Debouncer::default(),
+        limiter: Limiter::new(config.max_concurrency, config.max_queued_events),
+        renames: Renames::default(),
     });
 
+    let (Ok(mut hangup), Ok(mut terminate), Ok(mut interrupt)) = (
+        signal(SignalKind::hangup()),
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) else {
+        event!(Level::ERROR, "failed to set up signal handlers");
+        return ExitCode::FAILURE;
+    };
+
     let buffer = [0; 4096];
-    let Ok(events_stream) = inotify.into_event_stream(buffer) else {
+    let Ok(mut events_stream) = inotify.into_event_stream(buffer) else {
         return ExitCode::FAILURE;
     };
 
@@ -141,16 +429,82 @@ async fn main() -> ExitCode {
         });
     }
 
-    events_stream
-        .for_each_concurrent(None, |event| async {
-            let Ok(event) = event else {
-                event!(Level::ERROR, ?event, "failed to parse event");
-                return;
-            };
+    let started = Instant::now();
+    let mut socket = setup_socket();
+    let has_socket = socket.is_some();
 
-            handle_event(event, state.clone()).await;
-        })
-        .await;
+    let mut tasks = JoinSet::new();
+    loop {
+        tokio::select! {
+            request = recv_request(&mut socket) => match request {
+                Some((request, reply)) => {
+                    let _ = reply.send(handle_request(request, &state, started));
+                }
+                None => {
+                    event!(Level::WARN, "socket listener stopped");
+                    socket = None;
+                }
+            },
+            event = events_stream.next() => match event {
+                Some(Ok(event)) => {
+                    tasks.spawn(handle_event(event, state.clone()));
+                }
+                Some(Err(error)) => event!(Level::ERROR, ?error, "failed to parse event"),
+                None => break,
+            },
+            // Reap finished handlers so the set doesn't grow forever
+            Some(_) = tasks.join_next(), if !tasks.is_empty() => (),
+            _ = hangup.recv() => {
+                event!(Level::INFO, "SIGHUP");
+                state.reload_watches();
+            }
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+        }
+    }
 
+    shutdown(tasks, has_socket).await;
     ExitCode::SUCCESS
 }
+
+async fn recv_request(
+    socket: &mut Option<UnboundedReceiver<SocketRequest>>,
+) -> Option<SocketRequest> {
+    match socket {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for the running commands, up to the configured grace period
+#[tracing::instrument(skip_all)]
+async fn shutdown(mut tasks: JoinSet<()>, has_socket: bool) {
+    let grace_period = CONFIG.get().unwrap().shutdown_grace_period;
+    event!(
+        Level::INFO,
+        running = tasks.len(),
+        ?grace_period,
+        "SHUTDOWN"
+    );
+
+    let finished = tokio::time::timeout(grace_period, async {
+        while tasks.join_next().await.is_some() {}
+    })
+    .await;
+
+    if finished.is_err() {
+        event!(
+            Level::WARN,
+            running = tasks.len(),
+            "grace period expired: leaving commands running"
+        );
+    }
+
+    if has_socket {
+        if let Ok(ref socket) = *SOCKET {
+            if let Err(error) = fs::remove_file(socket) {
+                event!(Level::WARN, ?error, ?socket, "failed to remove socket");
+            }
+        }
+    }
+}
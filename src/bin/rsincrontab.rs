@@ -3,18 +3,27 @@ use figment::{
     providers::{Format, Toml},
     Figment,
 };
+use inotify::WatchMask;
+use nix::unistd::Uid;
 use rsincronlib::{
+    access,
     config::Config,
-    watch::{ParseWatchError, WatchData},
-    with_logging, SocketMessage, SOCKET, XDG,
+    events::MaskWrapper,
+    import::import_table,
+    lint::{check_table, Severity},
+    parser::ParseError,
+    socket::{send_request, DaemonStatus, Request, Response, WatchInfo},
+    table::{self, WriteTableError},
+    user::Credentials,
+    watch::{parse_table, ParseWatchError},
+    with_logging, SOCKET, XDG,
 };
 use std::{
-    fs::{self, File},
+    fs,
     io::{self, Write},
-    os::unix::net::UnixStream,
     path::{Path, PathBuf},
     process::{Command, ExitCode},
-    str::FromStr,
+    time::Duration,
 };
 use tracing::{event, Level};
 use uuid::Uuid;
@@ -24,6 +33,9 @@ enum Mode {
     Edit,
     List,
     Remove,
+    Check,
+    Status,
+    Import,
 }
 
 #[derive(Parser, Debug)]
@@ -32,6 +44,14 @@ struct Args {
     #[clap(value_enum)]
     mode: Mode,
 
+    /// Table to validate in `check` mode, reading standard input if omitted
+    /// or `-`; incron table, or directory of them, to translate in `import` mode
+    file: Option<PathBuf>,
+
+    /// In `import` mode, install the translated table instead of printing it
+    #[arg(long)]
+    install: bool,
+
     #[arg(
         short,
         long,
@@ -41,15 +61,161 @@ struct Args {
             .into_os_string()
         )]
     config: PathBuf,
+
+    /// Manage your table in the daemon-wide spool rather than your own one
+    #[arg(long)]
+    system: bool,
+
+    /// Manage this user's table in the system spool instead; root only
+    #[arg(short, long)]
+    user: Option<String>,
+}
+
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(10);
+const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Prefix of the comments explaining why a line was rejected, removed before saving
+const ANNOTATION: &str = "# rsincrontab: ";
+
+/// Asks a yes/no question on the terminal; an empty answer picks `default`
+/// while a closed standard input always means no
+fn prompt(question: &str, default: bool) -> bool {
+    print!("{question} ");
+    let _ = io::stdout().flush();
+
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(0) | Err(_) => false,
+        Ok(_) => match answer.trim().to_lowercase().as_str() {
+            "" => default,
+            answer => answer == "y" || answer == "yes",
+        },
+    }
+}
+
+fn strip_annotations(table: &str) -> String {
+    table
+        .lines()
+        .filter(|line| !line.starts_with(ANNOTATION))
+        .map(|line| format!("{line}\n"))
+        .collect()
+}
+
+/// Puts a comment describing each error right above the offending line
+fn annotate(table: &str, errors: &[ParseError]) -> String {
+    let mut annotated = String::new();
+
+    for (index, line) in table.lines().enumerate() {
+        for error in errors.iter().filter(|e| e.line_number == index + 1) {
+            annotated.push_str(&format!(
+                "{ANNOTATION}{} at column {}: {}\n",
+                error.headline(),
+                error.column(),
+                error.reason
+            ));
+        }
+        annotated.push_str(&format!("{line}\n"));
+    }
+
+    annotated
+}
+
+/// Waits for the daemon to have actually loaded the new table
+fn notify_daemon() {
+    match send_request(&Request::Reload, RELOAD_TIMEOUT) {
+        Ok(Ok(Response::Reloaded)) => event!(Level::INFO, "table reloaded by rsincrond"),
+        error => event!(
+            Level::WARN,
+            ?error,
+            socket = ?*SOCKET,
+            "rsincrond didn't acknowledge the update: reload daemon manually"
+        ),
+    }
+}
+
+fn print_status(status: &DaemonStatus, watches: &[WatchInfo], failed: &[WatchInfo]) {
+    println!(
+        "rsincrond {} (pid {}), up {}",
+        status.version,
+        status.pid,
+        humantime::format_duration(Duration::from_secs(status.uptime.as_secs()))
+    );
+    let tables: Vec<String> = status
+        .tables
+        .iter()
+        .map(|table| table.display().to_string())
+        .collect();
+    println!("tables: {}", tables.join(", "));
+    println!(
+        "{} active, {} failed, {} running command(s)\n",
+        status.watches, status.failed_watches, status.running_commands
+    );
+
+    let mut rows = vec![[
+        "WD",
+        "STATE",
+        "FIRED",
+        "USER",
+        "PATH",
+        "MASKS",
+        "ATTRIBUTES",
+    ]
+    .map(String::from)];
+    for watch in watches.iter().chain(failed) {
+        rows.push([
+            watch
+                .descriptor
+                .map_or(String::from("-"), |wd| wd.to_string()),
+            String::from(match (watch.descriptor, watch.paused) {
+                (None, _) => "failed",
+                (Some(_), true) => "paused",
+                (Some(_), false) => "active",
+            }),
+            watch.fired.to_string(),
+            watch.user.clone().unwrap_or(String::from("-")),
+            watch.path.display().to_string(),
+            MaskWrapper(WatchMask::from_bits_truncate(watch.masks)).to_string(),
+            if watch.attributes.is_empty() {
+                String::from("-")
+            } else {
+                watch.attributes.clone()
+            },
+        ]);
+    }
+
+    let mut widths = [0; 7];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    for row in rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
 }
 
 #[tracing::instrument]
 fn main() -> ExitCode {
     with_logging();
     let args = Args::parse();
+
+    // Everything here runs with the caller's say: the editor, the files read,
+    // the configuration
+    if Uid::effective() != Uid::current() {
+        eprintln!(
+            "rsincrontab must not be installed setuid: let root manage tables with -u <user>"
+        );
+        return ExitCode::FAILURE;
+    }
     let editor = std::env::var("EDITOR").unwrap_or("/usr/bin/vi".to_string());
 
-    let config: Config = match Figment::new().join(Toml::file(args.config)).extract() {
+    let mut config: Config = match Figment::new().join(Toml::file(args.config)).extract() {
         Ok(c) => c,
         Err(error) => {
             event!(
@@ -61,73 +227,327 @@ fn main() -> ExitCode {
         }
     };
 
+    if config.system || args.system || args.user.is_some() {
+        let Ok(Some(current)) = Credentials::current() else {
+            eprintln!("couldn't find out who you are");
+            return ExitCode::FAILURE;
+        };
+
+        // The spool is root's, which is the only one able to change it
+        let manages_table = matches!(args.mode, Mode::Edit | Mode::List | Mode::Remove);
+        if !current.uid.is_root() && (manages_table || args.install) {
+            eprintln!(
+                "system tables are managed by root: ask them to run `rsincrontab -u {}`",
+                current.name
+            );
+            return ExitCode::FAILURE;
+        }
+
+        let user = match args.user {
+            Some(name) if name != current.name && !current.uid.is_root() => {
+                eprintln!("only root may manage the table of another user");
+                return ExitCode::FAILURE;
+            }
+            Some(name) => match Credentials::lookup(&name) {
+                Ok(Some(user)) => user,
+                Ok(None) => {
+                    eprintln!("no such user `{name}`");
+                    return ExitCode::FAILURE;
+                }
+                Err(error) => {
+                    event!(Level::ERROR, ?error, name, "failed to look up user");
+                    return ExitCode::FAILURE;
+                }
+            },
+            None => current,
+        };
+
+        // Listing or removing a table stays possible for users denied since
+        // they installed it
+        if args.mode == Mode::Edit || args.install {
+            match access::is_permitted(&config, &user) {
+                Ok(true) => (),
+                Ok(false) => {
+                    eprintln!("{} isn't allowed to use rsincron", user.name);
+                    return ExitCode::FAILURE;
+                }
+                Err(error) => {
+                    event!(Level::ERROR, ?error, "failed to read access lists");
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+
+        config.watch_table_file = config.user_table_dir.join(user.name);
+    }
+
     match args.mode {
-        Mode::Edit => 'arm: {
+        Mode::Edit => {
             let tmpfile_path = std::env::temp_dir().join(Uuid::new_v4().to_string());
-            if Path::new(&config.watch_table_file).exists() {
-                fs::copy(&config.watch_table_file, &tmpfile_path)
-                    .expect("failed to open tmp file: exiting");
-            } else {
-                File::create(&tmpfile_path).expect("couldn't open tmp file for writing: exiting");
+            // Kept to detect whether someone else changed the table while editing
+            let snapshot = match table::read(&config.watch_table_file) {
+                Ok(snapshot) => snapshot,
+                Err(error) => {
+                    event!(Level::ERROR, ?error, filename = ?config.watch_table_file, "failed to read rsincron table");
+                    return ExitCode::FAILURE;
+                }
             };
+            fs::write(&tmpfile_path, snapshot.as_deref().unwrap_or_default())
+                .expect("couldn't open tmp file for writing: exiting");
+
+            let buf = loop {
+                let Ok(_exitstatus) = Command::new(editor.clone()).arg(&tmpfile_path).status()
+                else {
+                    event!(Level::ERROR, editor, "failed to open $EDITOR");
+                    return ExitCode::FAILURE;
+                };
+
+                let table =
+                    strip_annotations(&fs::read_to_string(&tmpfile_path).unwrap_or_default());
+                let mut buf = String::new();
+                let mut errors = Vec::new();
+                for (line, watch) in parse_table(&table) {
+                    match watch {
+                        Ok(_) | Err(ParseWatchError::IsComment) => {
+                            buf.push_str(&format!("{line}\n"))
+                        }
+                        Err(ParseWatchError::Invalid(error)) => errors.push(error),
+                    };
+                }
+
+                if errors.is_empty() {
+                    break buf;
+                }
+
+                for error in &errors {
+                    eprintln!("{error}\n");
+                }
+
+                if prompt("re-edit? [Y/n]", true) {
+                    if let Err(error) = fs::write(&tmpfile_path, annotate(&table, &errors)) {
+                        event!(
+                            Level::ERROR,
+                            ?error,
+                            ?tmpfile_path,
+                            "failed to annotate tmp file"
+                        );
+                        return ExitCode::FAILURE;
+                    }
+                    continue;
+                }
 
-            let Ok(_exitstatus) = Command::new(editor.clone()).arg(&tmpfile_path).status() else {
-                event!(Level::ERROR, editor, "failed to open $EDITOR");
+                if prompt(
+                    "discard invalid lines and install the table anyway? [y/N]",
+                    false,
+                ) {
+                    break buf;
+                }
+
+                eprintln!("table left unchanged, your edits are in {tmpfile_path:?}");
                 return ExitCode::FAILURE;
             };
 
-            let mut buf = String::new();
-            for line in fs::read_to_string(tmpfile_path).unwrap_or_default().lines() {
-                match WatchData::from_str(line) {
-                    Ok(_) | Err(ParseWatchError::IsComment) => buf.push_str(&format!("{line}\n")),
-                    _ => continue,
-                };
+            match table::write(&config.watch_table_file, &buf, snapshot.as_deref()) {
+                Ok(()) => (),
+                Err(WriteTableError::Modified) => {
+                    eprintln!(
+                        "table was modified by someone else while editing: not overwriting it, your edits are in {tmpfile_path:?}"
+                    );
+                    return ExitCode::FAILURE;
+                }
+                Err(WriteTableError::Io(error)) => {
+                    event!(Level::ERROR, ?error, filename = ?config.watch_table_file, "failed to write rsincron table");
+                    return ExitCode::FAILURE;
+                }
             }
 
-            if let Err(error) = fs::write(&config.watch_table_file, buf) {
-                event!(Level::ERROR, ?error, filename = ?config.watch_table_file, "failed to write rsincron table");
+            notify_daemon();
+        }
+
+        Mode::Import => {
+            let Some(source) = args.file.as_deref() else {
+                eprintln!("nothing to import: pass an incron table or a directory of them");
                 return ExitCode::FAILURE;
-            }
+            };
 
-            let socket = match *SOCKET {
-                Ok(ref socket) => socket.to_owned(),
-                Err(ref error) => {
+            let files = match source.is_dir() {
+                true => table::list(source),
+                false => Ok(vec![source.to_owned()]),
+            };
+            let files = match files {
+                Ok(files) => files,
+                Err(error) => {
                     event!(
-                        Level::WARN,
+                        Level::ERROR,
                         ?error,
-                        socket = ?*SOCKET,
-                        "failed to bind to socket: reload daemon manually"
+                        ?source,
+                        "failed to list incron tables"
                     );
-                    break 'arm;
+                    return ExitCode::FAILURE;
                 }
             };
 
-            if let Err(error) = UnixStream::connect(&socket).map(|mut stream| {
-                stream.write_all(
-                    bincode::serialize(&SocketMessage::UpdateWatches)
-                        .unwrap()
-                        .as_slice(),
+            if args.install && files.len() != 1 {
+                eprintln!("--install takes a single table: install each user's one with -u <user>");
+                return ExitCode::FAILURE;
+            }
+
+            let mut translated = String::new();
+            let mut errors = 0;
+            for file in &files {
+                let incron = match fs::read_to_string(file) {
+                    Ok(incron) => incron,
+                    Err(error) => {
+                        event!(Level::ERROR, ?error, ?file, "failed to read incron table");
+                        return ExitCode::FAILURE;
+                    }
+                };
+
+                let import = import_table(&incron);
+                for note in &import.notes {
+                    eprintln!(
+                        "{}:{}: {}: {}",
+                        file.display(),
+                        note.line_number,
+                        note.severity,
+                        note.message
+                    );
+                }
+                errors += import
+                    .notes
+                    .iter()
+                    .filter(|note| note.severity == Severity::Error)
+                    .count();
+
+                if files.len() > 1 {
+                    translated.push_str(&format!("# imported from {}\n", file.display()));
+                }
+                translated.push_str(&import.table);
+            }
+
+            if !args.install {
+                print!("{translated}");
+                return match errors {
+                    0 => ExitCode::SUCCESS,
+                    _ => ExitCode::FAILURE,
+                };
+            }
+
+            if errors > 0
+                && !prompt(
+                    &format!("{errors} line(s) couldn't be translated: install anyway? [y/N]"),
+                    false,
                 )
-            }) {
-                event!(
-                    Level::WARN,
-                    ?error,
-                    ?socket,
-                    "failed to send update socket message: reload daemon manually"
-                );
+            {
+                return ExitCode::FAILURE;
             }
+
+            let snapshot = match table::read(&config.watch_table_file) {
+                Ok(snapshot) => snapshot,
+                Err(error) => {
+                    event!(Level::ERROR, ?error, filename = ?config.watch_table_file, "failed to read rsincron table");
+                    return ExitCode::FAILURE;
+                }
+            };
+
+            let has_watches = snapshot.as_deref().is_some_and(|table| {
+                parse_table(table).any(|(_, watch)| watch != Err(ParseWatchError::IsComment))
+            });
+            if has_watches && !prompt("replace your current table? [y/N]", false) {
+                eprintln!("table left unchanged");
+                return ExitCode::FAILURE;
+            }
+
+            match table::write(&config.watch_table_file, &translated, snapshot.as_deref()) {
+                Ok(()) => (),
+                Err(WriteTableError::Modified) => {
+                    eprintln!("table was modified by someone else meanwhile: not overwriting it");
+                    return ExitCode::FAILURE;
+                }
+                Err(WriteTableError::Io(error)) => {
+                    event!(Level::ERROR, ?error, filename = ?config.watch_table_file, "failed to write rsincron table");
+                    return ExitCode::FAILURE;
+                }
+            }
+
+            notify_daemon();
+        }
+
+        Mode::Status => {
+            let query = |request: Request| match send_request(&request, STATUS_TIMEOUT) {
+                Ok(Ok(response)) => Ok(response),
+                Ok(Err(error)) => Err(format!("rsincrond refused {request:?}: {error:?}")),
+                Err(error) => Err(format!(
+                    "rsincrond isn't reachable on {:?}: {error}",
+                    SOCKET.as_deref().unwrap_or(Path::new("?"))
+                )),
+            };
+
+            let replies = (
+                query(Request::Status),
+                query(Request::ListWatches),
+                query(Request::ListFailedWatches),
+            );
+            let (Response::Status(status), Response::Watches(watches), Response::Watches(failed)) =
+                (match replies {
+                    (Ok(status), Ok(watches), Ok(failed)) => (status, watches, failed),
+                    (Err(error), _, _) | (_, Err(error), _) | (_, _, Err(error)) => {
+                        eprintln!("{error}");
+                        return ExitCode::FAILURE;
+                    }
+                })
+            else {
+                eprintln!("rsincrond sent an unexpected response");
+                return ExitCode::FAILURE;
+            };
+
+            print_status(&status, &watches, &failed);
         }
 
         Mode::List => {
             let _ = io::stdout().write_all(
-                fs::read_to_string(&config.watch_table_file)
+                table::read(&config.watch_table_file)
+                    .unwrap_or_default()
                     .unwrap_or_default()
                     .as_bytes(),
             );
         }
 
+        Mode::Check => {
+            let table = match args.file.as_deref() {
+                Some(file) if file != Path::new("-") => fs::read_to_string(file),
+                _ => io::read_to_string(io::stdin()),
+            };
+
+            let table = match table {
+                Ok(table) => table,
+                Err(error) => {
+                    event!(Level::ERROR, ?error, file = ?args.file, "failed to read table");
+                    return ExitCode::FAILURE;
+                }
+            };
+
+            let diagnostics = check_table(&table);
+            for diagnostic in &diagnostics {
+                eprintln!("{diagnostic}\n");
+            }
+
+            let errors = diagnostics
+                .iter()
+                .filter(|d| d.severity == Severity::Error)
+                .count();
+            eprintln!(
+                "{errors} error(s), {} warning(s)",
+                diagnostics.len() - errors
+            );
+
+            if errors > 0 {
+                return ExitCode::FAILURE;
+            }
+        }
+
         Mode::Remove => {
-            if let Err(error) = fs::remove_file(&config.watch_table_file) {
+            if let Err(error) = table::remove(&config.watch_table_file) {
                 event!(
                     Level::ERROR,
                     ?error,
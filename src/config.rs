@@ -1,10 +1,68 @@
-use crate::XDG;
+use crate::{
+    output::{Output, OutputLimits},
+    XDG,
+};
 use serde::Deserialize;
-use std::path::{Path, PathBuf};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 #[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct Config {
     pub watch_table_file: PathBuf,
+    /// Run as a single root daemon serving the tables below instead of
+    /// `watch_table_file`
+    pub system: bool,
+    /// Every file in here is a table whose commands run as root
+    pub system_table_dir: PathBuf,
+    /// Holds one table per user, named after them
+    pub user_table_dir: PathBuf,
+    /// Users allowed to have a table in `user_table_dir`, see [`crate::access`]
+    pub allow_file: PathBuf,
+    /// Users denied a table when `allow_file` doesn't exist
+    pub deny_file: PathBuf,
+    /// How long `rsincrond` waits for running commands when asked to stop
+    #[serde(with = "humantime_serde")]
+    pub shutdown_grace_period: Duration,
+    /// Commands running at once across every table, no limit if `0`
+    pub max_concurrency: usize,
+    /// Events each entry may have waiting for its `concurrency` limit, and
+    /// commands waiting for `max_concurrency`
+    pub max_queued_events: usize,
+    /// How long commands may run without a `timeout` of their own, no limit
+    /// if zero
+    #[serde(with = "humantime_serde")]
+    pub command_timeout: Duration,
+    /// How long commands past their timeout get between SIGTERM and SIGKILL
+    #[serde(with = "humantime_serde")]
+    pub kill_grace_period: Duration,
+    /// Where the output of commands without an `output` of their own goes
+    pub output: Output,
+    /// Bytes of output kept from each run
+    pub max_output_per_run: usize,
+    /// Size past which output files get rotated
+    pub output_file_max_size: u64,
+    /// Rotated output files kept
+    pub output_file_rotations: usize,
+    /// Run commands without a `shell` of their own through `/bin/sh -c`, as
+    /// incron does
+    pub shell: bool,
+    /// How long either half of a rename waits for the other one to run
+    /// commands once for both, no pairing if zero
+    #[serde(with = "humantime_serde")]
+    pub rename_window: Duration,
+}
+
+impl Config {
+    pub fn output_limits(&self) -> OutputLimits {
+        OutputLimits {
+            per_run: self.max_output_per_run,
+            file_size: self.output_file_max_size,
+            rotations: self.output_file_rotations,
+        }
+    }
 }
 
 impl Default for Config {
@@ -13,6 +71,22 @@ impl Default for Config {
             watch_table_file: XDG
                 .place_data_file(Path::new("rsincron.table"))
                 .expect("failed to create `rsincron.table`: is XDG_DATA_HOME set?"),
+            system: false,
+            system_table_dir: PathBuf::from("/etc/rsincron.d"),
+            user_table_dir: PathBuf::from("/var/spool/rsincron"),
+            allow_file: PathBuf::from("/etc/rsincron.allow"),
+            deny_file: PathBuf::from("/etc/rsincron.deny"),
+            shutdown_grace_period: Duration::from_secs(10),
+            max_concurrency: 0,
+            max_queued_events: 1024,
+            command_timeout: Duration::ZERO,
+            kill_grace_period: Duration::from_secs(5),
+            output: Output::default(),
+            max_output_per_run: 64 * 1024,
+            output_file_max_size: 1024 * 1024,
+            output_file_rotations: 3,
+            shell: false,
+            rename_window: Duration::from_millis(100),
         }
     }
 }
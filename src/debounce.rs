@@ -0,0 +1,225 @@
+//! Coalescing of event bursts for watches with a `debounce` window.
+//!
+//! The first event of a burst waits for the window to pass without another
+//! event, and later ones merge into it instead of running the command.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use inotify::Event;
+use tokio::time::{sleep_until, Instant};
+
+use crate::state::{EntryKey, Watch};
+
+/// Events sharing a key belong to the same burst
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BurstKey {
+    pub entry: EntryKey,
+    /// Set when bursts are told apart by file name as well
+    pub name: Option<OsString>,
+}
+
+impl BurstKey {
+    pub fn new(watch: &Watch, event: &Event<OsString>) -> Self {
+        Self {
+            entry: watch.key.clone(),
+            name: match watch.data.attributes.debounce_per_file {
+                true => event.name.clone(),
+                false => None,
+            },
+        }
+    }
+}
+
+/// Events merged into one
+#[derive(Debug)]
+pub struct Burst {
+    /// Watch the last event came from
+    pub watch: Watch,
+    /// Last event, with the masks of the whole burst
+    pub event: Event<OsString>,
+    /// Path before the rename, if the last event was one
+    pub renamed_from: Option<PathBuf>,
+    /// When the last event was read
+    pub time: SystemTime,
+    pub count: u64,
+    deadline: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct Debouncer {
+    bursts: Mutex<HashMap<BurstKey, Burst>>,
+}
+
+impl Debouncer {
+    /// Adds the event to the burst of `key`, then `None` if one was pending
+    /// already. Otherwise waits until `window` passes without another event
+    /// and returns the whole burst
+    pub async fn coalesce(
+        &self,
+        key: BurstKey,
+        watch: Watch,
+        event: Event<OsString>,
+        renamed_from: Option<PathBuf>,
+        time: SystemTime,
+        window: Duration,
+    ) -> Option<Burst> {
+        let mut deadline = Instant::now() + window;
+
+        {
+            let mut bursts = self.bursts.lock().unwrap();
+            if let Some(burst) = bursts.get_mut(&key) {
+                let mask = burst.event.mask | event.mask;
+                let name = event.name.clone().or_else(|| burst.event.name.take());
+
+                burst.watch = watch;
+                burst.event = Event {
+                    mask,
+                    name,
+                    ..event
+                };
+                burst.renamed_from = renamed_from;
+                burst.time = time;
+                burst.count += 1;
+                burst.deadline = deadline;
+                return None;
+            }
+
+            bursts.insert(
+                key.clone(),
+                Burst {
+                    watch,
+                    event,
+                    renamed_from,
+                    time,
+                    count: 1,
+                    deadline,
+                },
+            );
+        }
+
+        loop {
+            sleep_until(deadline).await;
+
+            let mut bursts = self.bursts.lock().unwrap();
+            deadline = bursts[&key].deadline;
+            if deadline <= Instant::now() {
+                return bursts.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::OsString,
+        path::PathBuf,
+        time::{Duration, SystemTime},
+    };
+
+    use inotify::{Event, EventMask, Inotify, WatchMask};
+
+    use crate::{
+        debounce::{BurstKey, Debouncer},
+        state::{EntryKey, Watch},
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn test_coalesce() {
+        let inotify = Inotify::init().unwrap();
+        let wd = inotify
+            .watches()
+            .add(std::env::temp_dir(), WatchMask::CREATE)
+            .unwrap();
+
+        let watch: Watch = Watch {
+            key: EntryKey {
+                table: PathBuf::from("/table"),
+                root: std::env::temp_dir(),
+            },
+            user: None,
+            data: format!(
+                "{} IN_MODIFY,debounce=1s echo",
+                std::env::temp_dir().display()
+            )
+            .parse()
+            .unwrap(),
+            line_number: 1,
+            fired: 0,
+        };
+        let event = |mask, name: &str| Event {
+            wd: wd.clone(),
+            mask,
+            cookie: 0,
+            name: Some(OsString::from(name)),
+        };
+        let key = BurstKey::new(&watch, &event(EventMask::MODIFY, "a"));
+        let window = Duration::from_secs(1);
+
+        let debouncer = Debouncer::default();
+        let first = debouncer.coalesce(
+            key.clone(),
+            watch.clone(),
+            event(EventMask::MODIFY, "a"),
+            None,
+            SystemTime::now(),
+            window,
+        );
+        let rest = async {
+            tokio::time::sleep(Duration::from_millis(600)).await;
+            let modify = event(EventMask::MODIFY, "b");
+            assert!(debouncer
+                .coalesce(
+                    key.clone(),
+                    watch.clone(),
+                    modify,
+                    None,
+                    SystemTime::now(),
+                    window
+                )
+                .await
+                .is_none());
+
+            // Each event pushes the end of the window back
+            tokio::time::sleep(Duration::from_millis(600)).await;
+            let close = event(EventMask::CLOSE_WRITE, "c");
+            assert!(debouncer
+                .coalesce(
+                    key.clone(),
+                    watch.clone(),
+                    close,
+                    None,
+                    SystemTime::now(),
+                    window,
+                )
+                .await
+                .is_none());
+        };
+
+        let (burst, ()) = tokio::join!(first, rest);
+        let burst = burst.unwrap();
+        assert_eq!(burst.count, 3);
+        assert_eq!(burst.event.mask, EventMask::MODIFY | EventMask::CLOSE_WRITE);
+        assert_eq!(burst.event.name, Some(OsString::from("c")));
+
+        // The burst is over: the next event starts another one
+        let burst = debouncer
+            .coalesce(
+                key,
+                watch,
+                event(EventMask::MODIFY, "d"),
+                None,
+                SystemTime::now(),
+                window,
+            )
+            .await
+            .unwrap();
+        assert_eq!(burst.count, 1);
+    }
+}
@@ -1,27 +1,182 @@
-use std::str::FromStr;
+use std::{fmt, str::FromStr};
 
-use inotify::WatchMask;
+use inotify::{EventMask, WatchMask};
 
 #[derive(Debug)]
 pub struct MaskWrapper(pub WatchMask);
 
+/// incron's flag against commands retriggering themselves, standing for
+/// `loopable=false`
+pub const NO_LOOP: &str = "IN_NO_LOOP";
+
+// Composite masks come first so that they are preferred when displaying
+const MASK_NAMES: &[(&str, WatchMask)] = &[
+    ("IN_ALL_EVENTS", WatchMask::ALL_EVENTS),
+    ("IN_CLOSE", WatchMask::CLOSE),
+    ("IN_MOVE", WatchMask::MOVE),
+    ("IN_ACCESS", WatchMask::ACCESS),
+    ("IN_ATTRIB", WatchMask::ATTRIB),
+    ("IN_CLOSE_WRITE", WatchMask::CLOSE_WRITE),
+    ("IN_CLOSE_NOWRITE", WatchMask::CLOSE_NOWRITE),
+    ("IN_CREATE", WatchMask::CREATE),
+    ("IN_DELETE", WatchMask::DELETE),
+    ("IN_DELETE_SELF", WatchMask::DELETE_SELF),
+    ("IN_MODIFY", WatchMask::MODIFY),
+    ("IN_MOVE_SELF", WatchMask::MOVE_SELF),
+    ("IN_MOVED_FROM", WatchMask::MOVED_FROM),
+    ("IN_MOVED_TO", WatchMask::MOVED_TO),
+    ("IN_OPEN", WatchMask::OPEN),
+    ("IN_ONLYDIR", WatchMask::ONLYDIR),
+    ("IN_DONT_FOLLOW", WatchMask::DONT_FOLLOW),
+    ("IN_EXCL_UNLINK", WatchMask::EXCL_UNLINK),
+    ("IN_ONESHOT", WatchMask::ONESHOT),
+    ("IN_MASK_ADD", WatchMask::MASK_ADD),
+];
+
 impl FromStr for MaskWrapper {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "IN_ACCESS" => Ok(MaskWrapper(WatchMask::ACCESS)),
-            "IN_CLOSE_WRITE" => Ok(MaskWrapper(WatchMask::CLOSE_WRITE)),
-            "IN_CLOSE_NOWRITE" => Ok(MaskWrapper(WatchMask::CLOSE_NOWRITE)),
-            "IN_CREATE" => Ok(MaskWrapper(WatchMask::CREATE)),
-            "IN_DELETE" => Ok(MaskWrapper(WatchMask::DELETE)),
-            "IN_DELETE_SELF" => Ok(MaskWrapper(WatchMask::DELETE_SELF)),
-            "IN_MODIFY" => Ok(MaskWrapper(WatchMask::MODIFY)),
-            "IN_MOVE_SELF" => Ok(MaskWrapper(WatchMask::MOVE_SELF)),
-            "IN_MOVED_FROM" => Ok(MaskWrapper(WatchMask::MOVED_FROM)),
-            "IN_MOVED_TO" => Ok(MaskWrapper(WatchMask::MOVED_TO)),
-            "IN_OPEN" => Ok(MaskWrapper(WatchMask::OPEN)),
-            "IN_ALL_EVENTS" => Ok(MaskWrapper(WatchMask::ALL_EVENTS)),
-            _ => Err(String::from("invalid descriptor")),
+        match MASK_NAMES.iter().find(|(name, _)| *name == s) {
+            Some(&(_, mask)) => Ok(MaskWrapper(mask)),
+            // Flags alone, `0` included, have inotify fail
+            None => parse_numeric(s)
+                .and_then(WatchMask::from_bits)
+                .filter(|mask| mask.intersects(WatchMask::ALL_EVENTS))
+                .map(MaskWrapper)
+                .ok_or(String::from("invalid descriptor")),
+        }
+    }
+}
+
+/// Comma separated mask names, the same way they are written in tables
+impl fmt::Display for MaskWrapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut remaining = self.0;
+        let mut names = Vec::new();
+
+        for &(name, mask) in MASK_NAMES {
+            if remaining.contains(mask) {
+                names.push(name.to_owned());
+                remaining.remove(mask);
+            }
+        }
+
+        if !remaining.is_empty() || names.is_empty() {
+            names.push(format!("{:#x}", remaining.bits()));
         }
+
+        f.write_str(&names.join(","))
+    }
+}
+
+// Flags events are reported with, each on its own
+const EVENT_NAMES: &[(&str, EventMask)] = &[
+    ("IN_ACCESS", EventMask::ACCESS),
+    ("IN_ATTRIB", EventMask::ATTRIB),
+    ("IN_CLOSE_WRITE", EventMask::CLOSE_WRITE),
+    ("IN_CLOSE_NOWRITE", EventMask::CLOSE_NOWRITE),
+    ("IN_CREATE", EventMask::CREATE),
+    ("IN_DELETE", EventMask::DELETE),
+    ("IN_DELETE_SELF", EventMask::DELETE_SELF),
+    ("IN_MODIFY", EventMask::MODIFY),
+    ("IN_MOVE_SELF", EventMask::MOVE_SELF),
+    ("IN_MOVED_FROM", EventMask::MOVED_FROM),
+    ("IN_MOVED_TO", EventMask::MOVED_TO),
+    ("IN_OPEN", EventMask::OPEN),
+    ("IN_ISDIR", EventMask::ISDIR),
+    ("IN_IGNORED", EventMask::IGNORED),
+    ("IN_Q_OVERFLOW", EventMask::Q_OVERFLOW),
+    ("IN_UNMOUNT", EventMask::UNMOUNT),
+];
+
+/// Comma separated names of the flags of an event, as incron passes them
+pub fn event_names(mask: EventMask) -> String {
+    EVENT_NAMES
+        .iter()
+        .filter(|(_, flag)| mask.contains(*flag))
+        .map(|(name, _)| *name)
+        .collect::<Vec<&str>>()
+        .join(",")
+}
+
+// Accepts raw masks as incron does: decimal (`4095`) or hexadecimal (`0xfff`)
+fn parse_numeric(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse::<u32>().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use inotify::{EventMask, WatchMask};
+
+    use crate::events::{event_names, MaskWrapper};
+
+    #[test]
+    fn test_named_masks() {
+        for (name, mask) in [
+            ("IN_ATTRIB", WatchMask::ATTRIB),
+            ("IN_CLOSE", WatchMask::CLOSE),
+            ("IN_MOVE", WatchMask::MOVE),
+            ("IN_ONLYDIR", WatchMask::ONLYDIR),
+            ("IN_DONT_FOLLOW", WatchMask::DONT_FOLLOW),
+            ("IN_EXCL_UNLINK", WatchMask::EXCL_UNLINK),
+            ("IN_ONESHOT", WatchMask::ONESHOT),
+            ("IN_MASK_ADD", WatchMask::MASK_ADD),
+        ] {
+            assert_eq!(name.parse::<MaskWrapper>().unwrap().0, mask);
+        }
+    }
+
+    #[test]
+    fn test_numeric_masks() {
+        assert_eq!("256".parse::<MaskWrapper>().unwrap().0, WatchMask::CREATE);
+        assert_eq!(
+            "0x300".parse::<MaskWrapper>().unwrap().0,
+            WatchMask::CREATE | WatchMask::DELETE
+        );
+        assert_eq!(
+            "0XFFF".parse::<MaskWrapper>().unwrap().0,
+            WatchMask::ALL_EVENTS
+        );
+
+        for mask in ["0", "0x0", "0x1000000", "0x10000000"] {
+            assert!(mask.parse::<MaskWrapper>().is_err());
+        }
+    }
+
+    #[test]
+    fn test_display_masks() {
+        let display = |mask: WatchMask| MaskWrapper(mask).to_string();
+
+        assert_eq!(
+            display(WatchMask::CREATE | WatchMask::DELETE),
+            "IN_CREATE,IN_DELETE"
+        );
+        assert_eq!(
+            display(WatchMask::MOVED_FROM | WatchMask::MOVED_TO | WatchMask::ONLYDIR),
+            "IN_MOVE,IN_ONLYDIR"
+        );
+        assert_eq!(display(WatchMask::ALL_EVENTS), "IN_ALL_EVENTS");
+        assert_eq!(display(WatchMask::empty()), "0x0");
+    }
+
+    #[test]
+    fn test_invalid_masks() {
+        assert!("IN_CRATE".parse::<MaskWrapper>().is_err());
+        assert!("0xzz".parse::<MaskWrapper>().is_err());
+        assert!("-1".parse::<MaskWrapper>().is_err());
+        // IN_IGNORED is only ever reported by the kernel, never requested
+        assert!("0x8000".parse::<MaskWrapper>().is_err());
+    }
+
+    #[test]
+    fn test_event_names() {
+        assert_eq!(
+            event_names(EventMask::CREATE | EventMask::ISDIR),
+            "IN_CREATE,IN_ISDIR"
+        );
+        assert_eq!(event_names(EventMask::IGNORED), "IN_IGNORED");
     }
 }
@@ -0,0 +1,316 @@
+//! Translation of incron tables into rsincron ones.
+//!
+//! incron runs every command through `sh -c` once its wildcards are expanded
+//! while rsincron runs them directly, so commands relying on the shell get
+//! `shell=true` to keep behaving the same.
+
+use std::{fs, path::Path};
+
+use inotify::WatchMask;
+
+use crate::{
+    events::{MaskWrapper, NO_LOOP},
+    lint::Severity,
+    parser::{split_command, CommandError},
+    watch::{Command, WatchData, WatchDataAttributes},
+};
+
+// Characters the shell gives a meaning to in an incron command
+const SHELL_CHARACTERS: &[char] = &[
+    '|', '&', ';', '<', '>', '(', ')', '`', '*', '?', '[', ']', '~', '{', '}', '\n',
+];
+
+/// A line that couldn't be translated exactly
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Note {
+    pub line_number: usize,
+    /// `Error` if the line was commented out, `Warning` if it behaves differently
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+    /// The rsincron table, with untranslatable lines commented out
+    pub table: String,
+    pub notes: Vec<Note>,
+}
+
+/// Options of an incron entry, defaulting the way incron does
+struct Options {
+    masks: WatchMask,
+    recursive: bool,
+    loopable: bool,
+    dotdirs: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            masks: WatchMask::empty(),
+            recursive: true,
+            loopable: true,
+            dotdirs: false,
+        }
+    }
+}
+
+/// Splits the path, in which incron lets whitespace be escaped with a
+/// backslash, from the rest of the line
+fn split_path(line: &str) -> (String, &str) {
+    let mut path = String::new();
+    let mut chars = line.char_indices();
+
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' => path.extend(chars.next().map(|(_, escaped)| escaped)),
+            c if c.is_whitespace() => return (path, &line[index..]),
+            c => path.push(c),
+        }
+    }
+
+    (path, "")
+}
+
+fn parse_options(options: &str) -> Result<Options, String> {
+    let mut parsed = Options::default();
+
+    for token in options.split(',') {
+        match token.split_once('=') {
+            Some((name, value)) => {
+                let Ok(value) = value.parse::<bool>() else {
+                    return Err(format!("expected `{name}=true` or `{name}=false`"));
+                };
+
+                match name {
+                    "recursive" => parsed.recursive = value,
+                    "loopable" => parsed.loopable = value,
+                    "dotdirs" => parsed.dotdirs = value,
+                    _ => return Err(format!("unknown attribute `{name}`")),
+                }
+            }
+            None if token == NO_LOOP => parsed.loopable = false,
+            None => match token.parse::<MaskWrapper>() {
+                Ok(mask) => parsed.masks |= mask.0,
+                Err(_) => return Err(format!("unknown mask `{token}`")),
+            },
+        }
+    }
+
+    if parsed.masks.is_empty() {
+        return Err(String::from("no event to watch"));
+    }
+
+    Ok(parsed)
+}
+
+/// Rewrites incron's wildcards for rsincron, along with whether the command
+/// needs the shell: whenever incron's would have done anything with it
+fn translate_command(command: &str, notes: &mut Vec<String>) -> Result<(Command, bool), String> {
+    if command.is_empty() {
+        return Err(String::from("nothing to execute"));
+    }
+
+    // Other errors come from `$` sequences, translated below
+    if let Err(error @ CommandError::UnbalancedQuotes) = split_command(command) {
+        return Err(error.to_string());
+    }
+
+    let mut translated = String::new();
+    // A leading `NAME=value` sets a variable for the command
+    let mut needs_shell = command
+        .split_whitespace()
+        .next()
+        .is_some_and(|program| program.contains('='));
+
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            needs_shell |= SHELL_CHARACTERS.contains(&c);
+            translated.push(c);
+            continue;
+        }
+
+        match chars.peek() {
+            Some(&wildcard @ ('@' | '#' | '&' | '%')) => {
+                chars.next();
+                translated.extend(['$', wildcard]);
+
+                let note = String::from("`$%` lists the event names in a different format");
+                if wildcard == '%' && !notes.contains(&note) {
+                    notes.push(note);
+                }
+            }
+            // incron hands `$$` as `$`, and any other `$` as is, to the shell
+            Some('$') => {
+                chars.next();
+                translated.push_str("$$");
+                needs_shell = true;
+            }
+            _ => {
+                translated.push_str("$$");
+                needs_shell = true;
+            }
+        }
+    }
+
+    let command = split_command(&translated).map_err(|error| error.to_string())?;
+    Ok((command, needs_shell))
+}
+
+/// `None` for comments and blank lines, otherwise the entry along with why
+/// it doesn't behave exactly as in incron
+fn translate_line(line: &str) -> Result<Option<(WatchData, Vec<String>)>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let (path, rest) = split_path(line);
+    let rest = rest.trim_start();
+    let (options, command) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+
+    if !Path::new(&path).is_absolute() {
+        return Err(String::from("path must be absolute"));
+    }
+
+    if path.contains(char::is_whitespace) {
+        return Err(String::from("rsincron paths can't contain whitespace"));
+    }
+
+    let options = parse_options(options)?;
+    let mut notes = Vec::new();
+
+    // Recursion means nothing on files
+    let recursive = options.recursive && fs::metadata(&path).map_or(true, |m| m.is_dir());
+    let walks = recursive && options.masks.contains(WatchMask::CREATE);
+    if recursive && !walks {
+        notes.push(String::from(
+            "subdirectories aren't watched: rsincron needs IN_CREATE to watch them",
+        ));
+    }
+
+    let (command, needs_shell) = translate_command(command.trim(), &mut notes)?;
+
+    let watch = WatchData {
+        path: path.into(),
+        masks: options.masks,
+        command,
+        attributes: WatchDataAttributes {
+            recursive: walks,
+            dotdirs: walks && options.dotdirs,
+            loopable: options.loopable,
+            shell: needs_shell.then_some(true),
+            ..WatchDataAttributes::default()
+        },
+    };
+
+    Ok(Some((watch, notes)))
+}
+
+/// Translates a whole incron table, line by line
+pub fn import_table(incron: &str) -> Import {
+    let mut import = Import {
+        table: String::new(),
+        notes: Vec::new(),
+    };
+
+    for (index, line) in incron.lines().enumerate() {
+        let note = |severity, message| Note {
+            line_number: index + 1,
+            severity,
+            message,
+        };
+
+        match translate_line(line) {
+            Ok(None) => import.table.push_str(&format!("{line}\n")),
+            Ok(Some((watch, notes))) => {
+                import.table.push_str(&format!("{watch}\n"));
+                import
+                    .notes
+                    .extend(notes.into_iter().map(|n| note(Severity::Warning, n)));
+            }
+            Err(reason) => {
+                import.table.push_str(&format!("# {line}\n"));
+                import.notes.push(note(Severity::Error, reason));
+            }
+        }
+    }
+
+    import
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{
+        import::{import_table, Note},
+        lint::{check_table, Severity},
+    };
+
+    const INCRON_DATA: &str = include_str!("../assets/test/incron-table");
+
+    #[test]
+    fn test_import_table() {
+        // An existing directory, and one that doesn't exist
+        let dir = std::env::temp_dir().join(format!("rsincron-import-{}", std::process::id()));
+        let (tmp, srv) = (dir.join("tmp"), dir.join("srv"));
+        fs::create_dir_all(&tmp).unwrap();
+        let (tmp, srv) = (tmp.display(), srv.display());
+
+        let incron = INCRON_DATA
+            .replace("/var/tmp", &tmp.to_string())
+            .replace("/srv", &srv.to_string());
+        let import = import_table(&incron);
+
+        assert_eq!(
+            import.table.lines().collect::<Vec<&str>>(),
+            vec![
+                String::from("# incrontab of a few services"),
+                format!("{tmp} IN_CREATE,IN_DELETE,recursive=true,loopable=true echo $@ $#"),
+                format!("# {srv}/in\\ box IN_CLOSE_WRITE process $@/$#"),
+                format!("{srv}/data IN_CLOSE_WRITE,loopable=true,shell=true cp $@/$# /backup/ && gzip $@/$#"),
+                format!("{srv}/logs IN_MODIFY,shell=true echo $% $$HOME"),
+                String::from("# relative IN_CREATE echo"),
+                format!("# {srv}/x IN_CRATE echo"),
+                format!("{srv}/tree IN_CREATE,recursive=true,dotdirs=true,loopable=true echo $&"),
+            ]
+        );
+
+        let note = |line_number, severity, message: &str| Note {
+            line_number,
+            severity,
+            message: message.to_owned(),
+        };
+        assert_eq!(
+            import.notes,
+            vec![
+                note(
+                    3,
+                    Severity::Error,
+                    "rsincron paths can't contain whitespace"
+                ),
+                note(
+                    4,
+                    Severity::Warning,
+                    "subdirectories aren't watched: rsincron needs IN_CREATE to watch them"
+                ),
+                note(
+                    5,
+                    Severity::Warning,
+                    "`$%` lists the event names in a different format"
+                ),
+                note(6, Severity::Error, "path must be absolute"),
+                note(7, Severity::Error, "unknown mask `IN_CRATE`"),
+            ]
+        );
+
+        // Whatever got translated must be a valid table
+        assert!(check_table(&import.table)
+            .iter()
+            .all(|diagnostic| diagnostic.severity == Severity::Warning));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
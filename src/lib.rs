@@ -1,14 +1,23 @@
+pub mod access;
 pub mod config;
+pub mod debounce;
 pub mod events;
+pub mod import;
+pub mod limit;
+pub mod lint;
+pub mod output;
 pub mod parser;
+pub mod rename;
+pub mod socket;
 pub mod state;
+pub mod table;
+pub mod template;
+pub mod user;
 pub mod watch;
 
 use lazy_static::lazy_static;
 use std::{io, path::PathBuf};
 use tracing_subscriber::EnvFilter;
-
-use serde::{Deserialize, Serialize};
 use xdg::BaseDirectories;
 
 lazy_static! {
@@ -17,11 +26,6 @@ lazy_static! {
     pub static ref SOCKET: Result<PathBuf, io::Error> = XDG.place_runtime_file("rsincron.socket");
 }
 
-#[derive(Serialize, Deserialize)]
-pub enum SocketMessage {
-    UpdateWatches,
-}
-
 pub fn with_logging() {
     tracing_subscriber::fmt()
         .with_writer(io::stderr)
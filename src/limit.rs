@@ -0,0 +1,349 @@
+//! Limits on how many commands run at once, globally and per entry
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use tokio::sync::{oneshot, Semaphore, SemaphorePermit, TryAcquireError};
+use tracing::{event, Level};
+
+use crate::{state::EntryKey, watch::Overflow};
+
+/// Waits for a running command of the entry to exit and hand its slot over
+struct Waiter {
+    turn: oneshot::Sender<u64>,
+    count: u64,
+}
+
+#[derive(Default)]
+struct Slots {
+    running: usize,
+    waiting: VecDeque<Waiter>,
+}
+
+pub struct Limiter {
+    global: Semaphore,
+    /// Global slots, taken or not
+    permits: usize,
+    /// Commands waiting for a global slot
+    global_waiting: AtomicUsize,
+    /// Events an entry may have waiting, and commands a global slot
+    queue_size: usize,
+    entries: Mutex<HashMap<EntryKey, Slots>>,
+}
+
+/// Lets a command run until dropped
+pub struct Permit<'a> {
+    limiter: &'a Limiter,
+    key: EntryKey,
+    /// Events the command runs for, including those collapsed into it
+    pub count: u64,
+    global: Option<SemaphorePermit<'a>>,
+}
+
+impl Limiter {
+    /// At most `max_concurrency` commands at once, no limit if `0`
+    pub fn new(max_concurrency: usize, queue_size: usize) -> Self {
+        let permits = match max_concurrency {
+            0 => Semaphore::MAX_PERMITS,
+            limit => limit,
+        };
+
+        Self {
+            global: Semaphore::new(permits),
+            permits,
+            global_waiting: AtomicUsize::new(0),
+            queue_size,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits for the command of `key` to be allowed to run; `None` if dropped
+    pub async fn acquire(
+        &self,
+        key: &EntryKey,
+        limit: Option<usize>,
+        overflow: Overflow,
+        count: u64,
+    ) -> Option<Permit<'_>> {
+        let turn = {
+            let mut entries = self.entries.lock().unwrap();
+            let slots = entries.entry(key.clone()).or_default();
+
+            match limit {
+                Some(limit) if slots.running >= limit => {
+                    let (turn, wait) = oneshot::channel();
+                    let mut waiter = Waiter { turn, count };
+
+                    match overflow {
+                        Overflow::DropNewest => {
+                            event!(
+                                Level::DEBUG,
+                                root = ?key.root,
+                                "concurrency limit reached: dropping event"
+                            );
+                            return None;
+                        }
+                        Overflow::Collapse => {
+                            let collapsed: u64 = slots.waiting.drain(..).map(|w| w.count).sum();
+                            waiter.count += collapsed;
+                        }
+                        Overflow::Queue if slots.waiting.len() >= self.queue_size => {
+                            event!(
+                                Level::WARN,
+                                root = ?key.root,
+                                queue_size = self.queue_size,
+                                "queue full: dropping newest event"
+                            );
+                            return None;
+                        }
+                        Overflow::DropOldest if self.queue_size == 0 => {
+                            event!(
+                                Level::WARN,
+                                root = ?key.root,
+                                "no queue: dropping event"
+                            );
+                            return None;
+                        }
+                        Overflow::DropOldest if slots.waiting.len() >= self.queue_size => {
+                            event!(
+                                Level::WARN,
+                                root = ?key.root,
+                                queue_size = self.queue_size,
+                                "queue full: dropping oldest event"
+                            );
+                            slots.waiting.pop_front();
+                        }
+                        Overflow::Queue | Overflow::DropOldest => (),
+                    }
+
+                    slots.waiting.push_back(waiter);
+                    Some(wait)
+                }
+                _ => {
+                    slots.running += 1;
+                    None
+                }
+            }
+        };
+
+        // A dropped sender means the event was dropped or collapsed while waiting
+        let count = match turn {
+            Some(wait) => wait.await.ok()?,
+            None => count,
+        };
+
+        let mut permit = Permit {
+            limiter: self,
+            key: key.clone(),
+            count,
+            global: None,
+        };
+        permit.global = Some(match self.global.try_acquire() {
+            Ok(global) => global,
+            Err(TryAcquireError::Closed) => return None,
+            Err(TryAcquireError::NoPermits) => {
+                let Some(_waiting) = GlobalWaiter::enter(self) else {
+                    event!(
+                        Level::WARN,
+                        root = ?key.root,
+                        queue_size = self.queue_size,
+                        "global queue full: dropping event"
+                    );
+                    return None;
+                };
+                self.global.acquire().await.ok()?
+            }
+        });
+        Some(permit)
+    }
+
+    /// Commands holding a global slot, that is running
+    pub fn running(&self) -> usize {
+        self.permits - self.global.available_permits()
+    }
+
+    /// Hands the slot of a command that exited to the next waiting one
+    fn release(&self, key: &EntryKey) {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(slots) = entries.get_mut(key) else {
+            return;
+        };
+
+        // Waiters whose event handler was cancelled can't take it
+        while let Some(waiter) = slots.waiting.pop_front() {
+            if waiter.turn.send(waiter.count).is_ok() {
+                return;
+            }
+        }
+
+        slots.running -= 1;
+        if slots.running == 0 {
+            entries.remove(key);
+        }
+    }
+}
+
+/// Counts a command among those waiting for a global slot, cancelled or not
+struct GlobalWaiter<'a>(&'a AtomicUsize);
+
+impl<'a> GlobalWaiter<'a> {
+    /// `None` if the queue is full
+    fn enter(limiter: &'a Limiter) -> Option<Self> {
+        let waiting = &limiter.global_waiting;
+        waiting
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                (count < limiter.queue_size).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| Self(waiting))
+    }
+}
+
+impl Drop for GlobalWaiter<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.limiter.release(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc, time::Duration};
+
+    use crate::{limit::Limiter, state::EntryKey, watch::Overflow};
+
+    fn key() -> EntryKey {
+        entry("/var/tmp")
+    }
+
+    fn entry(root: &str) -> EntryKey {
+        EntryKey {
+            table: PathBuf::from("/table"),
+            root: PathBuf::from(root),
+        }
+    }
+
+    /// Starts waiting for a slot in the background, returning the count it
+    /// eventually runs with
+    async fn acquire(
+        limiter: &Arc<Limiter>,
+        key: EntryKey,
+        concurrency: Option<usize>,
+        overflow: Overflow,
+        count: u64,
+    ) -> tokio::task::JoinHandle<Option<u64>> {
+        let limiter = limiter.clone();
+        let handle = tokio::spawn(async move {
+            let permit = limiter.acquire(&key, concurrency, overflow, count).await;
+            permit.map(|permit| permit.count)
+        });
+
+        // Lets it queue up
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        handle
+    }
+
+    async fn wait(
+        limiter: &Arc<Limiter>,
+        overflow: Overflow,
+        count: u64,
+    ) -> tokio::task::JoinHandle<Option<u64>> {
+        acquire(limiter, key(), Some(1), overflow, count).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_overflow() {
+        let limiter = Arc::new(Limiter::new(0, 1));
+
+        let running = limiter.acquire(&key(), Some(1), Overflow::Queue, 1).await;
+        let queued = wait(&limiter, Overflow::Queue, 1).await;
+        assert!(limiter
+            .acquire(&key(), Some(1), Overflow::Queue, 1)
+            .await
+            .is_none());
+        assert!(limiter
+            .acquire(&key(), Some(1), Overflow::DropNewest, 1)
+            .await
+            .is_none());
+        drop(running);
+        assert_eq!(queued.await.unwrap(), Some(1));
+
+        let running = limiter
+            .acquire(&key(), Some(1), Overflow::DropOldest, 1)
+            .await;
+        let oldest = wait(&limiter, Overflow::DropOldest, 1).await;
+        let newest = wait(&limiter, Overflow::DropOldest, 2).await;
+        drop(running);
+        assert_eq!(oldest.await.unwrap(), None);
+        assert_eq!(newest.await.unwrap(), Some(2));
+
+        let running = limiter
+            .acquire(&key(), Some(1), Overflow::Collapse, 1)
+            .await;
+        let first = wait(&limiter, Overflow::Collapse, 1).await;
+        let second = wait(&limiter, Overflow::Collapse, 2).await;
+        drop(running);
+        assert_eq!(first.await.unwrap(), None);
+        assert_eq!(second.await.unwrap(), Some(3));
+
+        // Without a queue, nothing waits
+        let limiter = Limiter::new(0, 0);
+        let _running = limiter
+            .acquire(&key(), Some(1), Overflow::DropOldest, 1)
+            .await;
+        assert!(limiter
+            .acquire(&key(), Some(1), Overflow::DropOldest, 1)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_global_limit() {
+        let limiter = Arc::new(Limiter::new(1, 1));
+
+        let running = limiter
+            .acquire(&entry("/srv"), None, Overflow::Queue, 1)
+            .await;
+        let waiting = acquire(&limiter, key(), None, Overflow::Queue, 1).await;
+        assert!(!waiting.is_finished());
+        assert_eq!(limiter.running(), 1);
+        drop(running);
+        assert_eq!(waiting.await.unwrap(), Some(1));
+        assert_eq!(limiter.running(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_global_queue() {
+        let limiter = Arc::new(Limiter::new(1, 1));
+
+        let running = limiter
+            .acquire(&entry("/a"), None, Overflow::Queue, 1)
+            .await;
+        let waiting = acquire(&limiter, entry("/b"), None, Overflow::Queue, 1).await;
+
+        // The queue is full, even across entries
+        assert!(limiter
+            .acquire(&entry("/c"), None, Overflow::Queue, 1)
+            .await
+            .is_none());
+
+        // Cancelled waits leave the queue
+        waiting.abort();
+        assert!(waiting.await.is_err());
+        let waiting = acquire(&limiter, entry("/c"), None, Overflow::Queue, 1).await;
+        assert!(!waiting.is_finished());
+
+        drop(running);
+        assert_eq!(waiting.await.unwrap(), Some(1));
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    collections::HashMap,
+    env,
+    ffi::OsStr,
+    fmt, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use inotify::WatchMask;
+use winnow::{token::rest, Parser};
+
+use crate::{
+    events::MaskWrapper,
+    parser::{parse_field, parse_option_tokens, Field, ParseError, WatchOption},
+    watch::{parse_table, AttributeError, ParseWatchError, WatchData, WatchDataAttributes},
+};
+
+// Events only ever reported for the children of a watched directory
+const DIRECTORY_EVENTS: WatchMask = WatchMask::CREATE
+    .union(WatchMask::DELETE)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::MOVED_TO);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub error: ParseError,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "{}: {}", self.severity, self.error),
+            Severity::Warning => {
+                write!(
+                    f,
+                    "{}: {} `{}`",
+                    self.severity, self.error.field, self.error.token
+                )?;
+                self.error.fmt_snippet(f)
+            }
+        }
+    }
+}
+
+/// Parses a whole table, returning every error along with warnings about
+/// lines that parse but are unlikely to do what their author wants.
+pub fn check_table(table: &str) -> Vec<Diagnostic> {
+    check_table_in(table, env::var_os("PATH").as_deref())
+}
+
+/// `check_table`, looking up programs in `search_path`
+fn check_table_in(table: &str, search_path: Option<&OsStr>) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    // Line each path was last watched on
+    let mut paths = HashMap::new();
+
+    for (index, (line, watch)) in parse_table(table).enumerate() {
+        match watch {
+            Ok(watch) => {
+                if let Some(previous) = paths.insert(watch.path.clone(), index + 1) {
+                    if let Ok((path, _, _)) =
+                        (parse_field, parse_option_tokens, rest).parse(line.trim())
+                    {
+                        let reason = format!("also watched on line {previous}, which is ignored");
+                        diagnostics.push(Diagnostic {
+                            severity: Severity::Warning,
+                            error: ParseError::new(line, path, Field::Path, reason)
+                                .at_line(index + 1),
+                        });
+                    }
+                }
+
+                diagnostics.extend(check_watch(line, &watch, search_path).into_iter().map(
+                    |error| Diagnostic {
+                        severity: Severity::Warning,
+                        error: error.at_line(index + 1),
+                    },
+                ))
+            }
+            Err(ParseWatchError::Invalid(error)) => diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                error,
+            }),
+            Err(ParseWatchError::IsComment) => (),
+        }
+    }
+
+    diagnostics
+}
+
+fn check_watch(line: &str, watch: &WatchData, search_path: Option<&OsStr>) -> Vec<ParseError> {
+    let mut warnings = Vec::new();
+
+    // `line` already parsed successfully: split it again only to locate tokens
+    let Ok((path, options, command)) = (parse_field, parse_option_tokens, rest).parse(line.trim())
+    else {
+        return warnings;
+    };
+
+    let metadata = fs::metadata(&watch.path);
+    if metadata.is_err() {
+        warnings.push(ParseError::new(
+            line,
+            path,
+            Field::Path,
+            "path does not exist",
+        ));
+    }
+    let is_file = metadata.is_ok_and(|metadata| !metadata.is_dir());
+
+    for token in options {
+        match token.parse::<WatchOption>() {
+            Ok(WatchOption::Attribute(name, value)) => {
+                if WatchDataAttributes::default().set(&name, &value) == Err(AttributeError::Unknown)
+                {
+                    warnings.push(ParseError::new(
+                        line,
+                        token,
+                        Field::Attribute,
+                        "unknown attribute, ignored",
+                    ));
+                } else if is_file && name == "recursive" && value == "true" {
+                    warnings.push(ParseError::new(
+                        line,
+                        token,
+                        Field::Attribute,
+                        "path is a file, there is nothing to recurse into",
+                    ));
+                } else if name == "dotdirs" && !watch.attributes.recursive {
+                    warnings.push(ParseError::new(
+                        line,
+                        token,
+                        Field::Attribute,
+                        "only applies to recursive watches",
+                    ));
+                } else if name == "debounce_per_file" && watch.attributes.debounce.is_none() {
+                    warnings.push(ParseError::new(
+                        line,
+                        token,
+                        Field::Attribute,
+                        "only applies to debounced watches",
+                    ));
+                } else if name == "overflow" && watch.attributes.concurrency.is_none() {
+                    warnings.push(ParseError::new(
+                        line,
+                        token,
+                        Field::Attribute,
+                        "only applies with `concurrency`",
+                    ));
+                }
+            }
+            Ok(WatchOption::Mask(mask)) if is_file => {
+                let Ok(MaskWrapper(mask)) = mask.parse::<MaskWrapper>() else {
+                    continue;
+                };
+
+                let events = mask & WatchMask::ALL_EVENTS;
+                if mask.contains(WatchMask::ONLYDIR) {
+                    warnings.push(ParseError::new(
+                        line,
+                        token,
+                        Field::Mask,
+                        "path is a file, the watch will fail",
+                    ));
+                } else if !events.is_empty() && DIRECTORY_EVENTS.contains(events) {
+                    warnings.push(ParseError::new(
+                        line,
+                        token,
+                        Field::Mask,
+                        "path is a file, this event only triggers for directories",
+                    ));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if !is_executable(&watch.command.program, search_path) {
+        let program = command.split_whitespace().next().unwrap_or(command);
+        warnings.push(ParseError::new(
+            line,
+            program,
+            Field::Command,
+            "program not found in $PATH or not executable",
+        ));
+    }
+
+    warnings
+}
+
+fn is_executable(program: &str, search_path: Option<&OsStr>) -> bool {
+    let is_executable_file = |path: &Path| {
+        fs::metadata(path)
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    };
+
+    if program.contains('/') {
+        return is_executable_file(Path::new(program));
+    }
+
+    search_path.is_some_and(|paths| {
+        env::split_paths(paths).any(|dir: PathBuf| is_executable_file(&dir.join(program)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, Permissions},
+        os::unix::fs::PermissionsExt,
+        path::{Path, PathBuf},
+    };
+
+    use crate::{
+        lint::{check_table_in, Severity},
+        parser::Field,
+    };
+
+    /// Directory the tables' `{dir}` stands for, holding a `file`, directories
+    /// `a` to `e` and a `bin` with the only program found, `sh`
+    fn fixture(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rsincron-lint-{name}-{}", std::process::id()));
+        for subdirectory in ["a", "b", "c", "d", "e", "bin"] {
+            fs::create_dir_all(dir.join(subdirectory)).unwrap();
+        }
+        fs::write(dir.join("bin/sh"), "").unwrap();
+        fs::set_permissions(dir.join("bin/sh"), Permissions::from_mode(0o755)).unwrap();
+        fs::write(dir.join("file"), "").unwrap();
+        dir
+    }
+
+    fn check(dir: &Path, table: &str) -> Vec<(Severity, Field, String)> {
+        let table = table.replace("{dir}", dir.to_str().unwrap());
+        check_table_in(&table, Some(dir.join("bin").as_os_str()))
+            .into_iter()
+            .map(|d| (d.severity, d.error.field, d.error.token))
+            .collect()
+    }
+
+    #[test]
+    fn test_clean_table() {
+        let dir = fixture("clean");
+        assert_eq!(
+            check(
+                &dir,
+                "# comment\n\n{dir} IN_CREATE,recursive=false sh -c true\n"
+            ),
+            vec![]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_errors_and_warnings() {
+        let dir = fixture("errors");
+        assert_eq!(
+            check(
+                &dir,
+                "{dir} IN_CRATE sh\n\
+                 {dir}/missing IN_CREATE sh\n\
+                 {dir}/a IN_CREATE,dotfiles=true sh\n\
+                 {dir}/b IN_CREATE,dotdirs=true sh\n\
+                 {dir}/c IN_CREATE,debounce_per_file=true sh\n\
+                 {dir}/d IN_CREATE,concurrency=2,overflow=collapse sh\n\
+                 {dir}/e IN_CREATE rsincron-missing-program $@\n\
+                 {dir}/file IN_MOVE,IN_MODIFY sh\n"
+            ),
+            vec![
+                (Severity::Error, Field::Mask, String::from("IN_CRATE")),
+                (
+                    Severity::Warning,
+                    Field::Path,
+                    format!("{}/missing", dir.display())
+                ),
+                (
+                    Severity::Warning,
+                    Field::Attribute,
+                    String::from("dotfiles=true")
+                ),
+                (
+                    Severity::Warning,
+                    Field::Attribute,
+                    String::from("dotdirs=true")
+                ),
+                (
+                    Severity::Warning,
+                    Field::Attribute,
+                    String::from("debounce_per_file=true")
+                ),
+                (
+                    Severity::Error,
+                    Field::Attribute,
+                    String::from("concurrency=2")
+                ),
+                (
+                    Severity::Warning,
+                    Field::Command,
+                    String::from("rsincron-missing-program")
+                ),
+                (Severity::Warning, Field::Mask, String::from("IN_MOVE")),
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_paths() {
+        let dir = fixture("duplicates");
+        assert_eq!(
+            check(
+                &dir,
+                "{dir} IN_CREATE sh\n{dir}/a IN_CREATE sh\n{dir} IN_DELETE sh\n"
+            ),
+            vec![(Severity::Warning, Field::Path, dir.display().to_string())]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
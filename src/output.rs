@@ -0,0 +1,433 @@
+//! Where the output of commands goes: logged, appended to a file or dropped
+
+use std::{
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::fs::{MetadataExt, OpenOptionsExt},
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use inotify::Event;
+use nix::{
+    errno::Errno,
+    fcntl::{self, OFlag},
+    libc,
+    sys::stat::Mode,
+    unistd::{self, fchown, Uid, UnlinkatFlags},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::{ChildStderr, ChildStdout},
+};
+use tracing::{event, Level};
+
+use crate::user::Credentials;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Output {
+    Discard,
+    /// Logged one event per line
+    Log(Level),
+    /// Appended to the file
+    File(PathBuf),
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self::Log(Level::INFO)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseOutputError;
+
+impl fmt::Display for ParseOutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected `discard`, `log:<level>` or `file:<absolute path>`")
+    }
+}
+
+impl FromStr for Output {
+    type Err = ParseOutputError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "discard" => Ok(Self::Discard),
+            None if s == "log" => Ok(Self::Log(Level::INFO)),
+            Some(("log", level)) => level.parse().map(Self::Log).map_err(|_| ParseOutputError),
+            Some(("file", path)) if Path::new(path).is_absolute() => Ok(Self::File(path.into())),
+            _ => Err(ParseOutputError),
+        }
+    }
+}
+
+impl TryFrom<String> for Output {
+    type Error = ParseOutputError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Output> for String {
+    fn from(output: Output) -> Self {
+        output.to_string()
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Discard => f.write_str("discard"),
+            Self::Log(level) => write!(f, "log:{}", level.as_str().to_lowercase()),
+            Self::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+/// Bounds on what the output of commands takes up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputLimits {
+    /// Bytes kept from each run, further lines being dropped
+    pub per_run: usize,
+    /// Size past which output files get rotated
+    pub file_size: u64,
+    /// Rotated files kept as `<file>.1`, `<file>.2`...
+    pub rotations: usize,
+}
+
+/// Receives the output of a single run
+pub struct Sink<'a> {
+    output: &'a Output,
+    limits: OutputLimits,
+    path: &'a Path,
+    event: &'a Event<OsString>,
+    /// Owner of the output file, if not the daemon's user
+    user: Option<&'a Credentials>,
+    /// Directory of the output file, which is opened and rotated within it
+    directory: Option<File>,
+    file: Option<File>,
+    written: usize,
+    truncated: bool,
+}
+
+/// Longer lines are split
+const MAX_LINE: usize = 8192;
+
+macro_rules! event_at {
+    ($level:expr, $($fields:tt)+) => {
+        match $level {
+            Level::ERROR => event!(Level::ERROR, $($fields)+),
+            Level::WARN => event!(Level::WARN, $($fields)+),
+            Level::INFO => event!(Level::INFO, $($fields)+),
+            Level::DEBUG => event!(Level::DEBUG, $($fields)+),
+            Level::TRACE => event!(Level::TRACE, $($fields)+),
+        }
+    };
+}
+
+impl<'a> Sink<'a> {
+    pub fn new(
+        output: &'a Output,
+        limits: OutputLimits,
+        path: &'a Path,
+        event: &'a Event<OsString>,
+        user: Option<&'a Credentials>,
+    ) -> Self {
+        Self {
+            output,
+            limits,
+            path,
+            event,
+            user: user.filter(|user| user.uid != Uid::effective()),
+            directory: None,
+            file: None,
+            written: 0,
+            truncated: false,
+        }
+    }
+
+    pub fn discards(&self) -> bool {
+        self.output == &Output::Discard
+    }
+
+    /// Reads both streams until they are closed
+    pub async fn capture(mut self, stdout: Option<ChildStdout>, stderr: Option<ChildStderr>) {
+        let (mut stdout, mut stderr) = (stdout.map(BufReader::new), stderr.map(BufReader::new));
+        let (mut out_line, mut err_line) = (Vec::new(), Vec::new());
+
+        while stdout.is_some() || stderr.is_some() {
+            tokio::select! {
+                read = read_chunk(&mut stdout, &mut out_line) => {
+                    self.take(read, &mut stdout, &mut out_line, "stdout")
+                }
+                read = read_chunk(&mut stderr, &mut err_line) => {
+                    self.take(read, &mut stderr, &mut err_line, "stderr")
+                }
+            }
+        }
+
+        if self.truncated {
+            event!(
+                Level::WARN,
+                path = ?self.path,
+                limit = self.limits.per_run,
+                "command output too long: truncated"
+            );
+        }
+    }
+
+    /// Writes out `line` once complete, or at the end of the stream
+    fn take<R>(
+        &mut self,
+        read: io::Result<usize>,
+        reader: &mut Option<R>,
+        line: &mut Vec<u8>,
+        stream: &str,
+    ) {
+        let ended = matches!(read, Ok(0) | Err(_));
+        if ended {
+            *reader = None;
+        }
+
+        let complete = line.last() == Some(&b'\n') || line.len() >= MAX_LINE;
+        if (ended && !line.is_empty()) || complete {
+            if line.last() == Some(&b'\n') {
+                line.pop();
+            }
+            self.write(line, stream);
+            line.clear();
+        }
+    }
+
+    fn write(&mut self, line: &[u8], stream: &str) {
+        self.written += line.len() + 1;
+        if self.written > self.limits.per_run {
+            self.truncated = true;
+            return;
+        }
+
+        match self.output {
+            Output::Discard => (),
+            Output::Log(level) => event_at!(
+                *level,
+                path = ?self.path,
+                mask = ?self.event.mask,
+                name = ?self.event.name,
+                stream,
+                line = %String::from_utf8_lossy(line)
+            ),
+            Output::File(file) => {
+                if let Err(error) = self.append(file, line) {
+                    event!(Level::WARN, ?error, ?file, "failed to write command output");
+                }
+            }
+        }
+    }
+
+    fn append(&mut self, path: &Path, line: &[u8]) -> io::Result<()> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let directory = match self.directory {
+            Some(ref directory) => directory,
+            None => self.directory.insert(open_directory(path, self.user)?),
+        };
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => self.file.insert(open(directory, name, self.user)?),
+        };
+
+        let size = file.metadata()?.len();
+        if size > 0 && size + line.len() as u64 >= self.limits.file_size {
+            rotate(directory, name, self.limits.rotations)?;
+            *file = open(directory, name, self.user)?;
+        }
+
+        file.write_all(&[line, b"\n"].concat())
+    }
+}
+
+/// Moves what `reader` has buffered into `line`; nothing is lost if cancelled
+async fn read_chunk<R: AsyncRead + Unpin>(
+    reader: &mut Option<BufReader<R>>,
+    line: &mut Vec<u8>,
+) -> io::Result<usize> {
+    let Some(reader) = reader else {
+        return std::future::pending().await;
+    };
+
+    let available = reader.fill_buf().await?;
+    let end = available
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map_or(available.len(), |newline| newline + 1)
+        .min(MAX_LINE - line.len());
+
+    line.extend_from_slice(&available[..end]);
+    reader.consume(end);
+    Ok(end)
+}
+
+/// Opens the directory of `path`, which has to belong to `user` if any.
+/// Files are opened relative to it so that it can't be swapped for a link
+fn open_directory(path: &Path, user: Option<&Credentials>) -> io::Result<File> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("/"),
+    };
+
+    let mut flags = libc::O_DIRECTORY;
+    if user.is_some() {
+        flags |= libc::O_NOFOLLOW;
+    }
+    let directory = OpenOptions::new()
+        .read(true)
+        .custom_flags(flags)
+        .open(parent)?;
+
+    if let Some(user) = user {
+        if directory.metadata()?.uid() != user.uid.as_raw() {
+            return Err(not_theirs());
+        }
+    }
+
+    Ok(directory)
+}
+
+fn not_theirs() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "not owned by the user")
+}
+
+/// Opens `name` within `directory` for appending, owned by `user` if any
+fn open(directory: &File, name: &OsStr, user: Option<&Credentials>) -> io::Result<File> {
+    let fd = fcntl::openat(
+        Some(directory.as_raw_fd()),
+        name,
+        OFlag::O_WRONLY | OFlag::O_APPEND | OFlag::O_CREAT | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+        Mode::from_bits_truncate(0o640),
+    )?;
+    // SAFETY: `openat` just returned this descriptor, which nothing else owns
+    let file = unsafe { File::from_raw_fd(fd) };
+
+    if let Some(user) = user {
+        let metadata = file.metadata()?;
+        if metadata.uid() != user.uid.as_raw() {
+            // Created just now
+            if metadata.uid() != Uid::effective().as_raw() || metadata.len() > 0 {
+                return Err(not_theirs());
+            }
+            fchown(file.as_raw_fd(), Some(user.uid), Some(user.gid))?;
+        }
+    }
+
+    Ok(file)
+}
+
+/// Shifts `name` to `name.1`, `name.1` to `name.2`... dropping the oldest
+fn rotate(directory: &File, name: &OsStr, rotations: usize) -> io::Result<()> {
+    let directory = Some(directory.as_raw_fd());
+    let rotated = |index: usize| {
+        let mut rotated = name.to_owned();
+        rotated.push(format!(".{index}"));
+        rotated
+    };
+
+    if rotations == 0 {
+        unistd::unlinkat(directory, name, UnlinkatFlags::NoRemoveDir)?;
+        return Ok(());
+    }
+
+    for index in (1..rotations).rev() {
+        match fcntl::renameat(
+            directory,
+            rotated(index).as_os_str(),
+            directory,
+            rotated(index + 1).as_os_str(),
+        ) {
+            Err(Errno::ENOENT) | Ok(()) => (),
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    fcntl::renameat(directory, name, directory, rotated(1).as_os_str())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, fs, path::PathBuf};
+
+    use tracing::Level;
+
+    use crate::{
+        output::{open_directory, rotate, Output},
+        user::Credentials,
+    };
+
+    /// A fresh directory for the test `name`
+    fn directory(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rsincron-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse_output() {
+        assert_eq!("discard".parse(), Ok(Output::Discard));
+        assert_eq!("log".parse(), Ok(Output::Log(Level::INFO)));
+        assert_eq!("log:warn".parse(), Ok(Output::Log(Level::WARN)));
+        assert_eq!(
+            "file:/var/log/x.log".parse(),
+            Ok(Output::File(PathBuf::from("/var/log/x.log")))
+        );
+        assert!("log:loud".parse::<Output>().is_err());
+        assert!("file:x.log".parse::<Output>().is_err());
+        assert!("stdout".parse::<Output>().is_err());
+
+        for output in ["discard", "log:debug", "file:/tmp/x"] {
+            assert_eq!(output.parse::<Output>().unwrap().to_string(), output);
+        }
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = directory("rotate");
+        let log = dir.join("out.log");
+        let directory = open_directory(&log, None).unwrap();
+
+        for content in ["a", "b", "c"] {
+            fs::write(&log, content).unwrap();
+            rotate(&directory, OsStr::new("out.log"), 2).unwrap();
+        }
+
+        assert!(!log.exists());
+        assert_eq!(fs::read_to_string(dir.join("out.log.1")).unwrap(), "c");
+        assert_eq!(fs::read_to_string(dir.join("out.log.2")).unwrap(), "b");
+        assert!(!dir.join("out.log.3").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_directory() {
+        let dir = directory("open");
+        fs::create_dir(dir.join("logs")).unwrap();
+        std::os::unix::fs::symlink("logs", dir.join("link")).unwrap();
+        let user = Credentials::current().unwrap().unwrap();
+
+        assert!(open_directory(&dir.join("logs/out.log"), Some(&user)).is_ok());
+        // The directory could be swapped for a link between check and use
+        assert!(open_directory(&dir.join("link/out.log"), Some(&user)).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,63 +1,209 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{convert::Infallible, fmt, ops::Range, path::PathBuf, str::FromStr};
 
-use crate::watch;
+use crate::{
+    template::{Template, TemplateError},
+    watch,
+};
 use winnow::{
     ascii::space0,
-    combinator::{delimited, rest, separated, terminated},
-    stream::AsChar,
-    token::take_till,
-    PResult, Parser,
+    combinator::{delimited, separated, terminated},
+    stream::{AsChar, Offset},
+    token::{rest, take_till},
+    ModalResult, Parser,
 };
 
 #[derive(Debug, PartialEq)]
 pub enum WatchOption {
     Mask(String),
-    Attribute(String, bool),
+    /// `name=value`, the value being checked by the attribute itself
+    Attribute(String, String),
 }
 
 impl FromStr for WatchOption {
-    type Err = ();
+    type Err = Infallible;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.contains('=') {
-            let Some((name, value)) = s.split_once('=') else {
-                return Err(());
-            };
+        if let Some((name, value)) = s.split_once('=') {
+            return Ok(Self::Attribute(name.to_owned(), value.to_owned()));
+        }
+
+        Ok(Self::Mask(s.to_owned()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Path,
+    Mask,
+    Attribute,
+    Command,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Field::Path => "path",
+            Field::Mask => "mask",
+            Field::Attribute => "attribute",
+            Field::Command => "command",
+        })
+    }
+}
+
+/// Error pointing at the token of a table line that failed to parse.
+///
+/// `columns` is a byte range into `line`; `line_number` starts from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line_number: usize,
+    pub line: String,
+    pub columns: Range<usize>,
+    pub field: Field,
+    pub token: String,
+    pub reason: String,
+}
 
-            let Ok(value) = value.parse::<bool>() else {
-                return Err(());
-            };
+impl ParseError {
+    /// `token` must be a subslice of `line`: its position is used as span
+    pub fn new(line: &str, token: &str, field: Field, reason: impl Into<String>) -> Self {
+        let start = token.offset_from(&line);
 
-            return Ok(Self::Attribute(name.to_owned(), value));
+        Self {
+            line_number: 1,
+            line: line.to_owned(),
+            columns: start..start + token.len(),
+            field,
+            token: token.to_owned(),
+            reason: reason.into(),
         }
+    }
 
-        Ok(Self::Mask(s.to_owned()))
+    pub fn at_line(self, line_number: usize) -> Self {
+        Self {
+            line_number,
+            ..self
+        }
+    }
+
+    /// 1-based column of the first character of the token
+    pub fn column(&self) -> usize {
+        self.line[..self.columns.start].chars().count() + 1
     }
 }
 
-pub fn parse_path(input: &mut &str) -> PResult<PathBuf> {
-    delimited(space0, take_till(0.., AsChar::is_space), space0)
-        .parse_to()
-        .parse_next(input)
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.headline())?;
+        self.fmt_snippet(f)
+    }
+}
+
+impl ParseError {
+    /// Short description of the error, e.g. "invalid mask `IN_CRATE`"
+    pub fn headline(&self) -> String {
+        if self.token.is_empty() {
+            format!("missing {}", self.field)
+        } else {
+            format!("invalid {} `{}`", self.field, self.token)
+        }
+    }
+
+    /// Writes the position of the token followed by the line with a caret under it
+    pub fn fmt_snippet(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let number = self.line_number.to_string();
+        let gutter = " ".repeat(number.len());
+        // Keep tabs so the caret lines up with the token however the terminal renders them
+        let padding: String = self.line[..self.columns.start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = "^".repeat(self.token.chars().count().max(1));
+
+        write!(
+            f,
+            " at line {}, column {}\n{gutter} |\n{number} | {}\n{gutter} | {padding}{carets} {}",
+            self.line_number,
+            self.column(),
+            self.line,
+            self.reason,
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn parse_field<'s>(input: &mut &'s str) -> ModalResult<&'s str> {
+    delimited(space0, take_till(0.., AsChar::is_space), space0).parse_next(input)
 }
 
-pub fn parse_masks(input: &mut &str) -> PResult<Vec<WatchOption>> {
+pub fn parse_path(input: &mut &str) -> ModalResult<PathBuf> {
+    parse_field.parse_to().parse_next(input)
+}
+
+pub fn parse_option_tokens<'s>(input: &mut &'s str) -> ModalResult<Vec<&'s str>> {
     terminated(
-        separated(1.., take_till(0.., (AsChar::is_space, ',')).parse_to(), ","),
+        separated(1.., take_till(0.., (AsChar::is_space, ',')), ","),
         space0,
     )
     .parse_next(input)
 }
 
-pub fn parse_command(input: &mut &str) -> PResult<watch::Command> {
-    rest.try_map(|r: &str| {
-        let argv = shell_words::split(r)?;
+pub fn parse_masks(input: &mut &str) -> ModalResult<Vec<WatchOption>> {
+    parse_option_tokens
+        .verify_map(|tokens| {
+            tokens
+                .into_iter()
+                .map(str::parse)
+                .collect::<Result<Vec<WatchOption>, Infallible>>()
+                .ok()
+        })
+        .parse_next(input)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    Empty,
+    UnbalancedQuotes,
+    /// Ranges are relative to the trimmed command
+    Template(TemplateError),
+}
 
-        Ok::<watch::Command, shell_words::ParseError>(watch::Command {
-            program: argv.first().ok_or(shell_words::ParseError)?.clone(),
-            argv: argv[1..].to_vec(),
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => f.write_str("nothing to execute"),
+            Self::UnbalancedQuotes => f.write_str("unbalanced quotes"),
+            Self::Template(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+pub fn split_command(command: &str) -> Result<watch::Command, CommandError> {
+    let line = command.trim();
+    let argv = shell_words::split(line).map_err(|_| CommandError::UnbalancedQuotes)?;
+    let (program, args) = argv.split_first().ok_or(CommandError::Empty)?;
+
+    // Quotes may join a `$` to what follows only once split: such errors
+    // point at the whole command
+    let template = |arg: &String| {
+        arg.parse::<Template>().map_err(|error| {
+            CommandError::Template(TemplateError {
+                range: 0..line.len(),
+                ..error
+            })
         })
+    };
+
+    Ok(watch::Command {
+        line: line.parse().map_err(CommandError::Template)?,
+        program: program.clone(),
+        argv: args.iter().map(template).collect::<Result<_, _>>()?,
     })
-    .parse_next(input)
+}
+
+pub fn parse_command(input: &mut &str) -> ModalResult<watch::Command> {
+    rest.try_map(split_command).parse_next(input)
 }
 
 #[cfg(test)]
@@ -66,7 +212,7 @@ mod tests {
 
     use winnow::{combinator::preceded, Parser};
 
-    use crate::parser::{parse_command, parse_masks, parse_path, WatchOption};
+    use crate::parser::{parse_command, parse_masks, parse_path, Field, ParseError, WatchOption};
 
     const LINE_DATA: &str = include_str!("../assets/test/test-line");
 
@@ -85,7 +231,7 @@ mod tests {
                 .unwrap(),
             vec![
                 WatchOption::Mask(String::from("IN_CREATE")),
-                WatchOption::Attribute(String::from("recursive"), true),
+                WatchOption::Attribute(String::from("recursive"), String::from("true")),
                 WatchOption::Mask(String::from("IN_DELETE"))
             ],
         );
@@ -100,8 +246,24 @@ mod tests {
                 .unwrap(),
             crate::watch::Command {
                 program: String::from("echo"),
-                argv: ["$@", "$#", "&>", "/dev/null"].map(String::from).to_vec()
+                argv: ["$@", "$#", "&>", "/dev/null"]
+                    .map(|arg| arg.parse().unwrap())
+                    .to_vec(),
+                line: "echo $@ $# &> /dev/null".parse().unwrap(),
             }
         );
     }
+
+    #[test]
+    fn test_render_error() {
+        let line = "/var/tmp\tIN_CRATE,recursive=true echo";
+        let error = ParseError::new(line, &line[9..17], Field::Mask, "unknown mask").at_line(12);
+
+        assert_eq!(error.column(), 10);
+        assert_eq!(
+            error.to_string(),
+            "invalid mask `IN_CRATE` at line 12, column 10\n   |\n12 | \
+             /var/tmp\tIN_CRATE,recursive=true echo\n   |         \t^^^^^^^^ unknown mask"
+        );
+    }
 }
@@ -0,0 +1,222 @@
+//! Pairing of the two halves of renames.
+//!
+//! inotify reports a rename as an `IN_MOVED_FROM` and an `IN_MOVED_TO` sharing
+//! a cookie, possibly from different watches. Whichever half is handled first
+//! waits a short window for the other one, so that the pair can run commands
+//! once, as a single event.
+
+use std::{collections::HashMap, ffi::OsString, sync::Mutex, time::Duration};
+
+use inotify::{Event, EventMask};
+use tokio::sync::oneshot;
+
+use crate::state::Watch;
+
+#[derive(Debug)]
+pub enum Move {
+    /// Both halves came within the window
+    Rename {
+        from: Event<OsString>,
+        to: Event<OsString>,
+    },
+    /// The other half never came, the file having crossed the edge of what is
+    /// watched
+    Unpaired(Event<OsString>),
+}
+
+/// Half waiting for the other one
+struct Pending {
+    event: Event<OsString>,
+    /// Dropped once paired
+    _paired: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+pub struct Renames {
+    pending: Mutex<HashMap<u32, Pending>>,
+}
+
+impl Renames {
+    /// Waits at most `window` for the other half of the move `event`. `None`
+    /// if the handler of the other half took care of the pair
+    pub async fn pair(&self, event: Event<OsString>, window: Duration) -> Option<Move> {
+        let paired = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.remove(&event.cookie) {
+                Some(other) => Ok(other.event),
+                None => {
+                    let (sender, paired) = oneshot::channel();
+                    let half = Pending {
+                        event: event.clone(),
+                        _paired: sender,
+                    };
+                    pending.insert(event.cookie, half);
+                    Err(paired)
+                }
+            }
+        };
+
+        let paired = match paired {
+            Ok(other) if event.mask.contains(EventMask::MOVED_TO) => {
+                return Some(Move::Rename {
+                    from: other,
+                    to: event,
+                })
+            }
+            Ok(other) => {
+                return Some(Move::Rename {
+                    from: event,
+                    to: other,
+                })
+            }
+            Err(paired) => paired,
+        };
+
+        if tokio::time::timeout(window, paired).await.is_ok() {
+            return None;
+        }
+
+        // Unless paired just as the window passed
+        let pending = self.pending.lock().unwrap().remove(&event.cookie);
+        pending.map(|_| Move::Unpaired(event))
+    }
+}
+
+/// The entries a rename runs commands of, with their event and whether it
+/// carries where the file came from. Entries watching both directories get
+/// both halves as one event, the others the half they watched
+pub fn rename_events<'a>(
+    sources: &'a [Watch],
+    destinations: &'a [Watch],
+    from: &Event<OsString>,
+    to: &Event<OsString>,
+) -> Vec<(&'a Watch, Event<OsString>, bool)> {
+    let mut events = Vec::new();
+
+    for watch in destinations {
+        let mut event = to.clone();
+        if sources.iter().any(|source| source.key == watch.key) {
+            event.mask |= EventMask::MOVED_FROM;
+        }
+        if watch.wants(&event) {
+            events.push((watch, event, true));
+        }
+    }
+
+    for watch in sources {
+        if destinations.iter().all(|other| other.key != watch.key) && watch.wants(from) {
+            events.push((watch, from.clone(), false));
+        }
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsString, path::PathBuf, time::Duration};
+
+    use inotify::{Event, EventMask, Inotify, WatchDescriptor, WatchMask};
+
+    use crate::{
+        rename::{rename_events, Move, Renames},
+        state::{EntryKey, Watch},
+    };
+
+    fn event(wd: &WatchDescriptor, mask: EventMask, cookie: u32, name: &str) -> Event<OsString> {
+        Event {
+            wd: wd.clone(),
+            mask,
+            cookie,
+            name: Some(OsString::from(name)),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pair() {
+        let inotify = Inotify::init().unwrap();
+        let wd = inotify
+            .watches()
+            .add(std::env::temp_dir(), WatchMask::MOVE)
+            .unwrap();
+        let event = |mask, cookie, name| event(&wd, mask, cookie, name);
+        let window = Duration::from_millis(100);
+        let renames = Renames::default();
+
+        // Either half may be handled first
+        for (first, second) in [
+            (EventMask::MOVED_FROM, EventMask::MOVED_TO),
+            (EventMask::MOVED_TO, EventMask::MOVED_FROM),
+        ] {
+            let (first, second) = tokio::join!(renames.pair(event(first, 1, "a"), window), async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                renames.pair(event(second, 1, "b"), window).await
+            });
+            let Some(Move::Rename { from, to }) = first.or(second) else {
+                panic!("expected a rename");
+            };
+            assert_eq!(from.mask, EventMask::MOVED_FROM);
+            assert_eq!(to.mask, EventMask::MOVED_TO);
+        }
+
+        let (from, to) = tokio::join!(
+            renames.pair(event(EventMask::MOVED_FROM, 2, "a"), window),
+            async {
+                tokio::time::sleep(Duration::from_millis(150)).await;
+                renames
+                    .pair(event(EventMask::MOVED_TO, 3, "b"), window)
+                    .await
+            }
+        );
+        assert!(matches!(from, Some(Move::Unpaired(event)) if event.cookie == 2));
+        assert!(matches!(to, Some(Move::Unpaired(event)) if event.cookie == 3));
+    }
+
+    #[test]
+    fn test_rename_events() {
+        let inotify = Inotify::init().unwrap();
+        let wd = |path| inotify.watches().add(path, WatchMask::MOVE).unwrap();
+        let (from, to) = (
+            event(&wd(std::env::temp_dir()), EventMask::MOVED_FROM, 1, "a"),
+            event(&wd(PathBuf::from("/")), EventMask::MOVED_TO, 1, "a"),
+        );
+        let watch = |root: &str, masks: &str| Watch {
+            key: EntryKey {
+                table: "/table".into(),
+                root: root.into(),
+            },
+            user: None,
+            data: format!("/{root} {masks} echo").parse().unwrap(),
+            line_number: 1,
+            fired: 0,
+        };
+
+        let sources = [
+            watch("left", "IN_MOVED_FROM"),
+            watch("both", "IN_MOVED_FROM"),
+        ];
+        let destinations = [
+            watch("arrived", "IN_MOVED_TO"),
+            watch("both", "IN_MOVED_FROM"),
+            // Moves in from elsewhere don't concern it
+            watch("moved-from", "IN_MOVED_FROM"),
+        ];
+        let events: Vec<_> = rename_events(&sources, &destinations, &from, &to)
+            .into_iter()
+            .map(|(watch, event, renamed)| (watch.key.root.clone(), event.mask, renamed))
+            .collect();
+
+        assert_eq!(
+            events,
+            [
+                ("arrived".into(), EventMask::MOVED_TO, true),
+                (
+                    "both".into(),
+                    EventMask::MOVED_FROM | EventMask::MOVED_TO,
+                    true
+                ),
+                ("left".into(), EventMask::MOVED_FROM, false),
+            ]
+        );
+    }
+}
@@ -0,0 +1,281 @@
+//! Control protocol spoken over `SOCKET`.
+//!
+//! Every message is a frame made of a big-endian `u32` length followed by
+//! the payload: a big-endian `u32` protocol version and the bincode-encoded
+//! body. The version comes first so that peers can reject each other cleanly
+//! even when the bodies aren't compatible anymore. A connection may carry any
+//! number of requests, each answered by exactly one [`Reply`].
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::UnixListener,
+    sync::{
+        mpsc::{self, UnboundedReceiver},
+        oneshot,
+    },
+};
+use tracing::{event, Level};
+
+use crate::SOCKET;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Refuse to allocate more than this for a single frame
+const MAX_FRAME_LENGTH: u32 = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Ping,
+    Reload,
+    ListWatches,
+    ListFailedWatches,
+    Status,
+    /// Stops running the command of the table entry watching this path
+    Pause(PathBuf),
+    Resume(PathBuf),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Pong,
+    Reloaded,
+    Watches(Vec<WatchInfo>),
+    Status(DaemonStatus),
+    Paused,
+    Resumed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    UnsupportedVersion { supported: u32 },
+    Malformed(String),
+    UnknownWatch(PathBuf),
+    Unavailable,
+}
+
+pub type Reply = Result<Response, ProtocolError>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WatchInfo {
+    /// `None` for watches waiting in `failed_watches`
+    pub descriptor: Option<i32>,
+    pub path: PathBuf,
+    /// Path of the table entry the watch comes from
+    pub root: PathBuf,
+    /// Table the entry was read from
+    pub table: PathBuf,
+    /// User the command runs as, `None` for the daemon's own user
+    pub user: Option<String>,
+    pub masks: u32,
+    /// Attributes differing from their default, as written in tables
+    pub attributes: String,
+    /// The command as written in the table
+    pub command: String,
+    pub paused: bool,
+    /// Times the command was run for this watch
+    pub fired: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DaemonStatus {
+    pub version: String,
+    pub pid: u32,
+    pub uptime: Duration,
+    /// Tables loaded by the last reload
+    pub tables: Vec<PathBuf>,
+    pub watches: usize,
+    pub failed_watches: usize,
+    pub running_commands: usize,
+}
+
+/// A request received on the socket, along with the channel to answer it on
+pub type SocketRequest = (Request, oneshot::Sender<Reply>);
+
+/// Encodes `body` into a complete frame, length prefix included
+pub fn encode<T: Serialize>(body: &T) -> Vec<u8> {
+    let body = bincode::serialize(body).expect("protocol types are always serializable");
+    let length = (4 + body.len()) as u32;
+
+    let mut frame = Vec::with_capacity(4 + length as usize);
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    frame.extend_from_slice(&body);
+    frame
+}
+
+/// Decodes the payload of a frame, i.e. everything after the length prefix
+pub fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T, ProtocolError> {
+    let Some((version, body)) = payload.split_first_chunk::<4>() else {
+        return Err(ProtocolError::Malformed(String::from("missing version")));
+    };
+
+    if u32::from_be_bytes(*version) != PROTOCOL_VERSION {
+        return Err(ProtocolError::UnsupportedVersion {
+            supported: PROTOCOL_VERSION,
+        });
+    }
+
+    bincode::deserialize(body).map_err(|error| ProtocolError::Malformed(error.to_string()))
+}
+
+fn check_length(length: [u8; 4]) -> io::Result<usize> {
+    match u32::from_be_bytes(length) {
+        length if length > MAX_FRAME_LENGTH => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {length} bytes is too big"),
+        )),
+        length => Ok(length as usize),
+    }
+}
+
+/// Reads the payload of the next frame; `None` once the peer hung up
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0; 4];
+    match reader.read_exact(&mut length).await {
+        Ok(_) => (),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error),
+    }
+
+    let mut payload = vec![0; check_length(length)?];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+/// Sends a single request to the daemon and waits for its reply
+pub fn send_request(request: &Request, timeout: Duration) -> io::Result<Reply> {
+    let socket = SOCKET
+        .as_deref()
+        .map_err(|error| io::Error::new(error.kind(), error.to_string()))?;
+
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.write_all(&encode(request))?;
+
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+    let mut payload = vec![0; check_length(length)?];
+    stream.read_exact(&mut payload)?;
+
+    Ok(decode::<Reply>(&payload).unwrap_or_else(Err))
+}
+
+/// Binds `SOCKET` and forwards every request received on it; `None` if the
+/// socket couldn't be set up. Must be called from within the tokio runtime.
+#[tracing::instrument(skip_all)]
+pub fn setup_socket() -> Option<UnboundedReceiver<SocketRequest>> {
+    let Ok(ref socket) = *SOCKET else {
+        event!(Level::WARN, error = ?SOCKET.as_deref().unwrap_err(), "failed to get socket path");
+        return None;
+    };
+
+    if Path::new(&socket).exists() {
+        if let Err(error) = fs::remove_file(socket) {
+            event!(Level::WARN, ?error, "failed to remove existing socket");
+            return None;
+        }
+    }
+
+    let listener = match UnixListener::bind(socket) {
+        Ok(l) => l,
+        Err(error) => {
+            event!(Level::WARN, ?error, "failed to bind to socket");
+            return None;
+        }
+    };
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(error) => {
+                    event!(Level::WARN, ?error, "failed to accept connection");
+                    continue;
+                }
+            };
+
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                while let Ok(Some(payload)) = read_frame(&mut stream).await {
+                    let reply = match decode::<Request>(&payload) {
+                        Ok(request) => {
+                            let (reply_tx, reply_rx) = oneshot::channel();
+                            if let Err(error) = tx.send((request, reply_tx)) {
+                                event!(
+                                    Level::WARN,
+                                    ?error,
+                                    "failed to send request through channel"
+                                );
+                            }
+
+                            // The daemon answers once it has dealt with the request
+                            reply_rx.await.unwrap_or(Err(ProtocolError::Unavailable))
+                        }
+                        Err(error) => Err(error),
+                    };
+
+                    if stream.write_all(&encode(&reply)).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+
+    Some(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::socket::{
+        decode, encode, read_frame, ProtocolError, Reply, Request, Response, PROTOCOL_VERSION,
+    };
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() {
+        let mut frames = encode(&Request::Pause(PathBuf::from("/var/tmp")));
+        frames.extend(encode::<Reply>(&Ok(Response::Pong)));
+        let mut input = frames.as_slice();
+
+        let payload = read_frame(&mut input).await.unwrap().unwrap();
+        assert_eq!(
+            decode::<Request>(&payload),
+            Ok(Request::Pause(PathBuf::from("/var/tmp")))
+        );
+        let payload = read_frame(&mut input).await.unwrap().unwrap();
+        assert_eq!(decode::<Reply>(&payload), Ok(Ok(Response::Pong)));
+        assert_eq!(read_frame(&mut input).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_version_mismatch() {
+        let mut frame = encode(&Request::Ping);
+        frame[4..8].copy_from_slice(&(PROTOCOL_VERSION + 1).to_be_bytes());
+
+        let payload = read_frame(&mut frame.as_slice()).await.unwrap().unwrap();
+        assert_eq!(
+            decode::<Request>(&payload),
+            Err(ProtocolError::UnsupportedVersion {
+                supported: PROTOCOL_VERSION
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame() {
+        let frame = u32::MAX.to_be_bytes();
+        assert!(read_frame(&mut frame.as_slice()).await.is_err());
+    }
+}
@@ -1,171 +1,671 @@
 use crate::{
+    access,
     config::Config,
-    watch::{ParseWatchError, WatchData, WatchDataAttributes},
-    SocketMessage, SOCKET,
+    debounce::Debouncer,
+    limit::Limiter,
+    rename::Renames,
+    socket::WatchInfo,
+    table,
+    user::Credentials,
+    watch::{parse_table, ParseWatchError, WatchData},
 };
-use inotify::{Inotify, WatchDescriptor, WatchMask};
-use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use inotify::{Event, EventMask, Inotify, WatchDescriptor, WatchMask};
 use tracing::{event, span, Level};
 
 use std::{
-    collections::HashMap,
-    fs,
-    io::Read,
-    os::unix::net::UnixListener,
-    path::Path,
-    str::FromStr,
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex, MutexGuard},
-    thread,
 };
 
-#[tracing::instrument(skip_all)]
-fn setup_socket(tx: UnboundedSender<SocketMessage>) -> bool {
-    let Ok(ref socket) = *SOCKET else {
-        event!(Level::WARN, error = ?SOCKET.as_deref().unwrap_err(), "failed to get socket path");
-        return false;
-    };
+/// Identifies a table entry: several tables may watch the same path
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EntryKey {
+    pub table: PathBuf,
+    pub root: PathBuf,
+}
 
-    if Path::new(&socket).exists() {
-        if let Err(error) = fs::remove_file(&socket) {
-            event!(Level::WARN, ?error, "failed to remove existing socket");
-            return false;
+/// A table entry along with the user its command runs as
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    user: Option<Credentials>,
+    data: WatchData,
+    line_number: usize,
+}
+
+/// A watch as added to inotify: either a table entry or one of the
+/// subdirectories of a recursive one
+#[derive(Debug, Clone)]
+pub struct Watch {
+    /// Table entry this watch was added for
+    pub key: EntryKey,
+    /// Owner of the table in system mode, `None` runs as the daemon's user
+    pub user: Option<Credentials>,
+    pub data: WatchData,
+    /// Line of the entry in its table
+    pub line_number: usize,
+    /// Times the command was run for this watch
+    pub fired: u64,
+}
+
+impl Watch {
+    /// Whether the entry asked for `event`, filters included: the descriptor
+    /// is shared by every entry watching the same inode, and maybe by a table
+    /// directory, so it reports events some didn't ask for
+    pub fn wants(&self, event: &Event<OsString>) -> bool {
+        if event.mask == EventMask::IGNORED {
+            return true;
         }
+
+        let is_dir = event.mask.contains(EventMask::ISDIR);
+        event.mask.bits() & self.data.masks.bits() != 0
+            && event
+                .name
+                .as_deref()
+                .is_none_or(|name| self.data.attributes.accepts(name, is_dir))
     }
 
-    let listener = match UnixListener::bind(socket) {
-        Ok(l) => l,
-        Err(error) => {
-            event!(Level::WARN, ?error, "failed to bind to socket");
-            return false;
+    fn subdirectory(&self, name: &OsStr) -> Self {
+        Self {
+            key: self.key.clone(),
+            user: self.user.clone(),
+            data: WatchData {
+                path: self.data.path.join(name),
+                ..self.data.clone()
+            },
+            line_number: self.line_number,
+            fired: 0,
         }
-    };
-
-    thread::spawn(move || {
-        for mut stream in listener.incoming().flatten() {
-            let mut buffer = [0; 100];
-            if stream.read(&mut buffer).is_err() {
-                return;
-            }
+    }
+}
 
-            let Ok(SocketMessage::UpdateWatches) = bincode::deserialize(&buffer) else {
-                return;
-            };
+// Several table entries may watch the same inode, hence share a descriptor
+type Watches = HashMap<WatchDescriptor, Vec<Watch>>;
 
-            if let Err(error) = tx.send(SocketMessage::UpdateWatches) {
-                event!(
-                    Level::WARN,
-                    ?error,
-                    "failed to send update message through channel"
-                );
-            }
-        }
-    });
+// Events on the table's directory that may mean the table was (re)written or removed
+const TABLE_EVENTS: WatchMask = WatchMask::CREATE
+    .union(WatchMask::CLOSE_WRITE)
+    .union(WatchMask::MOVED_TO)
+    .union(WatchMask::DELETE)
+    .union(WatchMask::MOVED_FROM);
 
-    true
+fn walks_subdirectories(watch: &WatchData) -> bool {
+    watch.attributes.recursive && watch.masks.contains(WatchMask::CREATE)
 }
 
-type Watches = HashMap<WatchDescriptor, WatchData>;
+/// Whether both walk the same subdirectories, so that one can take over the
+/// watches of the other
+fn walks_alike(a: &WatchData, b: &WatchData) -> bool {
+    walks_subdirectories(a) == walks_subdirectories(b)
+        && (!walks_subdirectories(a)
+            || (a.attributes.dotdirs, &a.attributes.exclude)
+                == (b.attributes.dotdirs, &b.attributes.exclude))
+}
 
 pub struct State {
-    pub failed_watches: Vec<WatchData>,
-    pub has_socket: bool,
-    pub rx: UnboundedReceiver<SocketMessage>,
+    pub failed_watches: Vec<Watch>,
 
     config: Config,
     inotify_watches: inotify::Watches,
     watches: Watches,
+    table: HashMap<EntryKey, Entry>,
+    /// Tables found by the last reload
+    tables: Vec<PathBuf>,
+    /// Descriptors of the table directories, along with their path
+    table_watches: HashMap<WatchDescriptor, PathBuf>,
+    paused: HashSet<PathBuf>,
+    /// Entries whose command is running, and ignore events meanwhile, with
+    /// how many times it is
+    running: HashMap<EntryKey, usize>,
 
     span: tracing::Span,
 }
 
 impl State {
     pub fn new(inotify: &mut Inotify, config: Config) -> Self {
-        let (tx, rx) = mpsc::unbounded_channel();
-
         Self {
-            rx,
             config,
             watches: HashMap::new(),
+            table: HashMap::new(),
+            tables: Vec::new(),
+            table_watches: HashMap::new(),
+            paused: HashSet::new(),
+            running: HashMap::new(),
             failed_watches: Vec::new(),
-            has_socket: setup_socket(tx),
             inotify_watches: inotify.watches(),
             span: span!(Level::INFO, "state"),
         }
     }
 
+    /// Directories whose changes trigger a reload
+    fn table_directories(&self) -> Vec<&Path> {
+        if self.config.system {
+            vec![&self.config.system_table_dir, &self.config.user_table_dir]
+        } else {
+            self.config.watch_table_file.parent().into_iter().collect()
+        }
+    }
+
+    /// Every table to load along with the user its commands run as
+    fn table_files(&self) -> Vec<(PathBuf, Option<Credentials>)> {
+        if !self.config.system {
+            return vec![(self.config.watch_table_file.clone(), None)];
+        }
+
+        let list = |directory: &Path| {
+            table::list(directory)
+                .inspect_err(|error| {
+                    event!(Level::WARN, ?error, ?directory, "failed to list tables")
+                })
+                .unwrap_or_default()
+        };
+
+        let mut tables: Vec<_> = list(&self.config.system_table_dir)
+            .into_iter()
+            .map(|path| (path, None))
+            .collect();
+
+        for path in list(&self.config.user_table_dir) {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let user = match Credentials::lookup(&name) {
+                Ok(Some(user)) => user,
+                Ok(None) => {
+                    event!(Level::WARN, table = ?path, "no such user: ignoring table");
+                    continue;
+                }
+                Err(error) => {
+                    event!(Level::WARN, ?error, table = ?path, "failed to look up user: ignoring table");
+                    continue;
+                }
+            };
+
+            match access::is_permitted(&self.config, &user) {
+                Ok(true) => (),
+                Ok(false) => {
+                    event!(Level::WARN, table = ?path, "user isn't allowed to use rsincron: ignoring table");
+                    continue;
+                }
+                Err(error) => {
+                    event!(Level::WARN, ?error, table = ?path, "failed to read access lists: ignoring table");
+                    continue;
+                }
+            }
+
+            // Anyone able to write a table gets to run commands as its user
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.uid() == user.uid.as_raw() || metadata.uid() == 0 => {
+                    tables.push((path, Some(user)))
+                }
+                Ok(metadata) => event!(
+                    Level::WARN,
+                    table = ?path,
+                    owner = metadata.uid(),
+                    "table isn't owned by its user or root: ignoring table"
+                ),
+                Err(error) => event!(Level::WARN, ?error, table = ?path, "failed to stat table"),
+            }
+        }
+
+        tables
+    }
+
+    /// Applies the differences between the tables on disk and the ones loaded
+    /// last: unchanged entries keep their inotify watches untouched
     #[tracing::instrument(skip_all, parent = &self.span)]
     pub fn reload_watches(&mut self) {
-        self.watches.clear();
-        event!(Level::INFO, table = ?self.config.watch_table_file, "RELOAD");
+        event!(Level::INFO, system = self.config.system, "RELOAD");
         event!(Level::DEBUG, ?self.watches);
-        let table_content = match fs::read_to_string(&self.config.watch_table_file) {
-            Ok(table_content) => table_content,
-            _ => {
-                event!(Level::ERROR, filename = ?self.config.watch_table_file, "failed to read file");
-                panic!("failed to read watch table file");
-            }
-        };
 
-        for line in table_content.lines() {
-            let watch = match WatchData::from_str(line) {
-                Ok(w) => w,
-                Err(error) => {
-                    if error != ParseWatchError::IsComment {
-                        event!(Level::WARN, ?error, line, "failed to parse line");
-                    }
+        let tables = self.table_files();
+        let mut table = HashMap::new();
 
+        for (path, user) in &tables {
+            // A missing table simply means no watches, while on any other error
+            // the watches loaded from the last readable table are kept
+            let table_content = match table::read(path) {
+                Ok(table_content) => table_content.unwrap_or_default(),
+                Err(error) => {
+                    event!(
+                        Level::ERROR,
+                        ?error,
+                        filename = ?path,
+                        "failed to read file: keeping current watches"
+                    );
+                    table.extend(
+                        self.table
+                            .iter()
+                            .filter(|(key, _)| &key.table == path)
+                            .map(|(key, entry)| (key.clone(), entry.clone())),
+                    );
                     continue;
                 }
             };
 
-            self.add_watch(watch);
+            // `parse_table` yields every line, comments included
+            for (index, (_, watch)) in parse_table(&table_content).enumerate() {
+                let watch = match watch {
+                    Ok(w) => w,
+                    Err(ParseWatchError::IsComment) => continue,
+                    Err(ParseWatchError::Invalid(error)) => {
+                        event!(Level::WARN, table = ?path, "failed to parse line: {error}");
+                        continue;
+                    }
+                };
+
+                let key = EntryKey {
+                    table: path.clone(),
+                    root: watch.path.clone(),
+                };
+                let entry = Entry {
+                    user: user.clone(),
+                    data: watch,
+                    line_number: index + 1,
+                };
+                if let Some(previous) = table.insert(key, entry) {
+                    event!(
+                        Level::WARN,
+                        table = ?path,
+                        ignored = previous.line_number,
+                        line = index + 1,
+                        "path watched on several lines: only the last one is used"
+                    );
+                }
+            }
+        }
+
+        let removed: Vec<EntryKey> = self
+            .table
+            .keys()
+            .filter(|key| !table.contains_key(*key))
+            .cloned()
+            .collect();
+        for key in &removed {
+            self.remove_root(key);
         }
+
+        let (mut added, mut changed) = (0, 0);
+        for (key, entry) in &table {
+            match self.table.get(key) {
+                None => {
+                    self.add_root(key, entry);
+                    added += 1;
+                }
+                Some(current) if current == entry => (),
+                Some(current) => {
+                    if walks_alike(&current.data, &entry.data) {
+                        self.update_root(key, entry);
+                    } else {
+                        self.remove_root(key);
+                        self.add_root(key, entry);
+                    }
+                    changed += 1;
+                }
+            }
+        }
+
+        self.paused
+            .retain(|root| table.keys().any(|key| &key.root == root));
+        self.table = table;
+        self.tables = tables.into_iter().map(|(path, _)| path).collect();
+        // Last: updated watches on the same directory may have replaced its mask
+        self.watch_table_directories();
+
+        event!(
+            Level::INFO,
+            added,
+            removed = removed.len(),
+            changed,
+            "RELOADED"
+        );
     }
 
     #[tracing::instrument(skip_all, parent = &self.span)]
     pub fn recover_watches(&mut self) {
-        self.failed_watches.retain(|watch| {
-            let Ok(descriptor) = self.inotify_watches.add(watch.path.clone(), watch.masks) else {
-                return true;
+        self.recover_table_directories();
+
+        for watch in std::mem::take(&mut self.failed_watches) {
+            if !self.add_watch(watch.clone()) {
+                self.failed_watches.push(watch);
+            }
+        }
+    }
+
+    /// Watches the directories holding the tables so that they get reloaded
+    /// whenever a table is replaced, removed or created again
+    fn watch_table_directories(&mut self) {
+        let directories: Vec<PathBuf> = self
+            .table_directories()
+            .into_iter()
+            .map(Path::to_owned)
+            .collect();
+
+        for directory in directories {
+            if let Err(error) = self.watch_table_directory(&directory) {
+                event!(
+                    Level::WARN,
+                    ?error,
+                    ?directory,
+                    "failed to watch table directory: retrying later"
+                )
+            }
+        }
+    }
+
+    fn watch_table_directory(&mut self, directory: &Path) -> io::Result<()> {
+        // MASK_ADD: the directory might be watched by a table too
+        let descriptor = self
+            .inotify_watches
+            .add(directory, TABLE_EVENTS | WatchMask::MASK_ADD)?;
+        self.table_watches.insert(descriptor, directory.to_owned());
+        Ok(())
+    }
+
+    /// Watches again the table directories which couldn't be, or were removed
+    /// since, reloading once they are back as tables may have changed meanwhile
+    fn recover_table_directories(&mut self) {
+        let unwatched: Vec<PathBuf> = self
+            .table_directories()
+            .into_iter()
+            .filter(|directory| {
+                !self
+                    .table_watches
+                    .values()
+                    .any(|watched| watched == directory)
+            })
+            .map(Path::to_owned)
+            .collect();
+
+        let mut recovered = false;
+        for directory in unwatched {
+            recovered |= self.watch_table_directory(&directory).is_ok();
+        }
+
+        if recovered {
+            self.reload_watches();
+        }
+    }
+
+    pub fn is_table_event(&self, event: &Event<OsString>) -> bool {
+        if !self.table_watches.contains_key(&event.wd) {
+            return false;
+        }
+
+        match event.name.as_deref() {
+            Some(name) if self.config.system => table::is_table_name(name),
+            name => name == self.config.watch_table_file.file_name(),
+        }
+    }
+
+    pub fn get_watches(&self, wd: &WatchDescriptor) -> Vec<Watch> {
+        self.watches.get(wd).cloned().unwrap_or_default()
+    }
+
+    pub fn is_paused(&self, root: &Path) -> bool {
+        self.paused.contains(root)
+    }
+
+    pub fn record_fired(&mut self, wd: &WatchDescriptor, key: &EntryKey) {
+        if let Some(watch) = self
+            .watches
+            .get_mut(wd)
+            .and_then(|watches| watches.iter_mut().find(|watch| &watch.key == key))
+        {
+            watch.fired += 1;
+        }
+    }
+
+    /// `false` if the command of the entry is running already, unless
+    /// `alongside` lets it run once more
+    pub fn start_run(&mut self, key: &EntryKey, alongside: bool) -> bool {
+        let runs = self.running.entry(key.clone()).or_default();
+        if *runs > 0 && !alongside {
+            return false;
+        }
+
+        *runs += 1;
+        true
+    }
+
+    pub fn finish_run(&mut self, key: &EntryKey) {
+        if let Some(runs) = self.running.get_mut(key) {
+            *runs -= 1;
+            if *runs == 0 {
+                self.running.remove(key);
+            }
+        }
+    }
+
+    pub fn is_running(&self, key: &EntryKey) -> bool {
+        self.running.contains_key(key)
+    }
+
+    fn has_root(&self, root: &Path) -> bool {
+        self.table.keys().any(|key| key.root == root)
+    }
+
+    /// Pauses the table entries for `root`; `false` if there is no such entry
+    pub fn pause(&mut self, root: &Path) -> bool {
+        self.has_root(root) && {
+            self.paused.insert(root.to_owned());
+            true
+        }
+    }
+
+    pub fn resume(&mut self, root: &Path) -> bool {
+        self.has_root(root) && {
+            self.paused.remove(root);
+            true
+        }
+    }
+
+    fn info(&self, descriptor: Option<&WatchDescriptor>, watch: &Watch) -> WatchInfo {
+        WatchInfo {
+            descriptor: descriptor.map(WatchDescriptor::get_watch_descriptor_id),
+            path: watch.data.path.clone(),
+            root: watch.key.root.clone(),
+            table: watch.key.table.clone(),
+            user: watch.user.as_ref().map(|user| user.name.clone()),
+            masks: watch.data.masks.bits(),
+            attributes: watch.data.attributes.to_string(),
+            command: watch.data.command.line.to_string(),
+            paused: self.paused.contains(&watch.key.root),
+            fired: watch.fired,
+        }
+    }
+
+    pub fn list_watches(&self) -> Vec<WatchInfo> {
+        let mut watches: Vec<WatchInfo> = self
+            .watches
+            .iter()
+            .flat_map(|(wd, watches)| watches.iter().map(move |watch| (wd, watch)))
+            .map(|(wd, watch)| self.info(Some(wd), watch))
+            .collect();
+        watches.sort_by(|a, b| (a.descriptor, &a.table).cmp(&(b.descriptor, &b.table)));
+        watches
+    }
+
+    pub fn list_failed_watches(&self) -> Vec<WatchInfo> {
+        self.failed_watches
+            .iter()
+            .map(|watch| self.info(None, watch))
+            .collect()
+    }
+
+    pub fn tables(&self) -> Vec<PathBuf> {
+        self.tables.clone()
+    }
+
+    /// Moves the watches the kernel dropped to `failed_watches` to be retried later
+    pub fn fail_watches(&mut self, wd: &WatchDescriptor) -> Vec<Watch> {
+        self.table_watches.remove(wd);
+        let watches = self.watches.remove(wd).unwrap_or_default();
+        self.failed_watches.extend(watches.iter().cloned());
+        watches
+    }
+
+    /// Adds a directory created inside the recursive watches on `wd`
+    #[tracing::instrument(skip_all, parent = &self.span)]
+    pub fn add_subdirectory(&mut self, wd: &WatchDescriptor, name: &OsStr) {
+        let parents = self.get_watches(wd);
+
+        for parent in parents
+            .iter()
+            .filter(|w| walks_subdirectories(&w.data) && w.data.attributes.walks_into(name))
+        {
+            let watch = parent.subdirectory(name);
+            if !self.add_watch(watch.clone()) {
+                event!(Level::WARN, ?watch.data.path, "failed to add watch");
+            }
+        }
+    }
+
+    /// Sets the kernel mask of `wd` back to what its remaining watches need,
+    /// removing it altogether once nothing needs it anymore
+    fn sync_descriptor(&mut self, wd: &WatchDescriptor, path: &Path) {
+        let mut masks = self
+            .watches
+            .get(wd)
+            .into_iter()
+            .flatten()
+            .fold(WatchMask::empty(), |masks, watch| masks | watch.data.masks);
+        if self.table_watches.contains_key(wd) {
+            masks |= TABLE_EVENTS;
+        }
+
+        if masks.is_empty() {
+            self.watches.remove(wd);
+            if let Err(error) = self.inotify_watches.remove(wd.clone()) {
+                event!(Level::DEBUG, ?error, "watch already gone");
+            }
+        } else if let Err(error) = self.inotify_watches.add(path, masks) {
+            event!(Level::WARN, ?error, ?path, "failed to update watch");
+        }
+    }
+
+    fn add_root(&mut self, key: &EntryKey, entry: &Entry) {
+        let watch = Watch {
+            key: key.clone(),
+            user: entry.user.clone(),
+            data: entry.data.clone(),
+            line_number: entry.line_number,
+            fired: 0,
+        };
+
+        if !self.add_watch(watch.clone()) {
+            event!(Level::WARN, ?watch.data.path, "failed to add watch: retrying later");
+            self.failed_watches.push(watch);
+        }
+    }
+
+    #[tracing::instrument(skip_all, parent = &self.span)]
+    fn remove_root(&mut self, key: &EntryKey) {
+        let mut emptied = Vec::new();
+
+        for (wd, watches) in &mut self.watches {
+            let Some(index) = watches.iter().position(|watch| &watch.key == key) else {
+                continue;
             };
 
+            let watch = watches.remove(index);
             event!(
                 Level::INFO,
-                id = descriptor.get_watch_descriptor_id(),
-                ?watch.path,
-                ?watch.masks,
-                "ADD"
+                id = wd.get_watch_descriptor_id(),
+                ?watch.data.path,
+                table = ?key.table,
+                "REMOVE"
             );
-            self.watches.insert(descriptor, watch.clone());
-            false
-        });
-    }
+            emptied.push((wd.clone(), watch.data.path));
+        }
 
-    pub fn get_watch(&self, wd: &WatchDescriptor) -> Option<&WatchData> {
-        self.watches.get(wd)
+        for (wd, path) in emptied {
+            self.sync_descriptor(&wd, &path);
+        }
+
+        self.failed_watches.retain(|watch| &watch.key != key);
     }
 
-    pub fn remove_watch(&mut self, wd: &WatchDescriptor) -> Option<WatchData> {
-        self.watches.remove(wd)
+    /// Swaps masks, command and attributes of every watch coming from the
+    /// same table entry without walking the directory tree again
+    #[tracing::instrument(skip_all, parent = &self.span)]
+    fn update_root(&mut self, key: &EntryKey, entry: &Entry) {
+        let update = |watch: &mut Watch| {
+            watch.data = WatchData {
+                path: watch.data.path.clone(),
+                ..entry.data.clone()
+            };
+            watch.user = entry.user.clone();
+            watch.line_number = entry.line_number;
+        };
+
+        let mut updated = Vec::new();
+        for (wd, watches) in &mut self.watches {
+            for watch in watches.iter_mut().filter(|watch| &watch.key == key) {
+                update(watch);
+                event!(
+                    Level::INFO,
+                    id = wd.get_watch_descriptor_id(),
+                    ?watch.data.path,
+                    ?watch.data.masks,
+                    "UPDATE"
+                );
+                updated.push((wd.clone(), watch.data.path.clone()));
+            }
+        }
+
+        for (wd, path) in updated {
+            self.sync_descriptor(&wd, &path);
+        }
+
+        self.failed_watches
+            .iter_mut()
+            .filter(|watch| &watch.key == key)
+            .for_each(update);
     }
 
+    /// Adds the watch and, if recursive, every subdirectory below it; only
+    /// returns `false` if the watch itself couldn't be added. Paths the user
+    /// of the entry couldn't list are skipped, as events would tell them what
+    /// they hold
     #[tracing::instrument(skip_all, parent = &self.span)]
-    fn add_watch(&mut self, watch: WatchData) {
-        let Ok(descriptor) = self.inotify_watches.add(&watch.path, watch.masks) else {
-            event!(Level::WARN, "failed to add watch");
-            return;
+    fn add_watch(&mut self, watch: Watch) -> bool {
+        if let Some(ref user) = watch.user {
+            if let Ok(false) = user.may_list(&watch.data.path) {
+                event!(
+                    Level::WARN,
+                    ?watch.data.path,
+                    user = user.name,
+                    "user can't list the path: not watching"
+                );
+                return true;
+            }
+        }
+
+        // MASK_ADD: other table entries might already watch the same inode
+        let Ok(descriptor) = self
+            .inotify_watches
+            .add(&watch.data.path, watch.data.masks | WatchMask::MASK_ADD)
+        else {
+            return false;
         };
 
         event!(
             Level::INFO,
             id = descriptor.get_watch_descriptor_id(),
-            ?watch.path,
-            ?watch.masks,
+            ?watch.data.path,
+            ?watch.data.masks,
+            table = ?watch.key.table,
             "ADD"
         );
 
-        if watch.attributes.recursive && watch.masks.contains(WatchMask::CREATE) {
-            for entry in fs::read_dir(&watch.path).unwrap() {
+        if walks_subdirectories(&watch.data) {
+            let entries = fs::read_dir(&watch.data.path).inspect_err(
+                |error| event!(Level::WARN, ?error, ?watch.data.path, "failed to read directory"),
+            );
+
+            for entry in entries.into_iter().flatten() {
                 let Ok(entry) = entry else {
                     continue;
                 };
@@ -174,29 +674,38 @@ impl State {
                     continue;
                 };
 
-                if !metadata.is_dir() {
+                if !metadata.is_dir() || !watch.data.attributes.walks_into(&entry.file_name()) {
                     continue;
                 }
 
-                let watch = WatchData {
-                    path: watch.path.join(entry.file_name()),
-                    attributes: WatchDataAttributes {
-                        starting: false,
-                        recursive: true,
-                    },
-                    ..watch.clone()
-                };
-
-                self.add_watch(watch)
+                let subdirectory = watch.subdirectory(&entry.file_name());
+                if !self.add_watch(subdirectory.clone()) {
+                    event!(Level::WARN, ?subdirectory.data.path, "failed to add watch");
+                }
             }
         };
 
-        self.watches.insert(descriptor, watch);
+        // A directory renamed within the tree, or added back after failing,
+        // may still be watched for the same entry under its former path
+        let watches = self.watches.entry(descriptor).or_default();
+        match watches.iter_mut().find(|current| current.key == watch.key) {
+            Some(current) => {
+                *current = Watch {
+                    fired: current.fired,
+                    ..watch
+                }
+            }
+            None => watches.push(watch),
+        }
+        true
     }
 }
 
 pub struct Shared {
     pub state: Mutex<State>,
+    pub debouncer: Debouncer,
+    pub limiter: Limiter,
+    pub renames: Renames,
 }
 
 impl Shared {
@@ -211,29 +720,322 @@ impl Shared {
         self.with_lock().recover_watches();
     }
 
-    pub fn get_watch(&self, wd: &WatchDescriptor) -> Option<WatchData> {
-        self.with_lock().get_watch(wd).cloned()
+    pub fn is_table_event(&self, event: &Event<OsString>) -> bool {
+        self.with_lock().is_table_event(event)
+    }
+
+    pub fn get_watches(&self, wd: &WatchDescriptor) -> Vec<Watch> {
+        self.with_lock().get_watches(wd)
+    }
+
+    pub fn fail_watches(&self, wd: &WatchDescriptor) -> Vec<Watch> {
+        self.with_lock().fail_watches(wd)
+    }
+
+    pub fn is_paused(&self, root: &Path) -> bool {
+        self.with_lock().is_paused(root)
+    }
+
+    pub fn record_fired(&self, wd: &WatchDescriptor, key: &EntryKey) {
+        self.with_lock().record_fired(wd, key)
     }
 
-    pub fn remove_watch(&self, wd: &WatchDescriptor) -> Option<WatchData> {
-        self.with_lock().remove_watch(wd)
+    /// Marks the command of `watch` as running until the returned guard is
+    /// dropped; `None` if it is running already, as the event is then its
+    /// doing. Loopable entries run regardless, and so do debounced ones,
+    /// whose events were checked on arrival
+    pub fn start_run(self: &Arc<Self>, watch: &Watch) -> Option<RunGuard> {
+        let attributes = &watch.data.attributes;
+        let alongside = attributes.loopable || attributes.debounce.is_some();
+
+        self.with_lock()
+            .start_run(&watch.key, alongside)
+            .then(|| RunGuard {
+                shared: self.clone(),
+                key: watch.key.clone(),
+            })
+    }
+
+    pub fn is_running(&self, key: &EntryKey) -> bool {
+        self.with_lock().is_running(key)
+    }
+
+    pub fn pause(&self, root: &Path) -> bool {
+        self.with_lock().pause(root)
+    }
+
+    pub fn resume(&self, root: &Path) -> bool {
+        self.with_lock().resume(root)
+    }
+
+    pub fn list_watches(&self) -> Vec<WatchInfo> {
+        self.with_lock().list_watches()
     }
 
-    pub fn has_socket(&self) -> bool {
-        self.with_lock().has_socket
+    pub fn list_failed_watches(&self) -> Vec<WatchInfo> {
+        self.with_lock().list_failed_watches()
     }
 
-    pub fn rx_try_recv(&self) -> Result<SocketMessage, mpsc::error::TryRecvError> {
-        self.with_lock().rx.try_recv()
+    pub fn tables(&self) -> Vec<PathBuf> {
+        self.with_lock().tables()
     }
 
-    pub fn push_failed_watch(&self, watch: WatchData) {
-        self.with_lock().failed_watches.push(watch)
+    /// Number of active and failed watches
+    pub fn count_watches(&self) -> (usize, usize) {
+        let state = self.with_lock();
+        (
+            state.watches.values().map(Vec::len).sum(),
+            state.failed_watches.len(),
+        )
     }
 
-    pub fn unset_socket(&self) {
-        self.with_lock().has_socket = false;
+    pub fn add_subdirectory(&self, wd: &WatchDescriptor, name: &OsStr) {
+        self.with_lock().add_subdirectory(wd, name)
     }
 }
 
 pub type ArcShared = Arc<Shared>;
+
+/// Held while the command of an entry runs
+pub struct RunGuard {
+    shared: ArcShared,
+    key: EntryKey,
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        self.shared.with_lock().finish_run(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        fs::{self, Permissions},
+        os::unix::fs::PermissionsExt,
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    use inotify::{Inotify, WatchMask};
+    use nix::unistd::{Gid, Uid};
+
+    use crate::{
+        config::Config,
+        debounce::Debouncer,
+        limit::Limiter,
+        rename::Renames,
+        state::{Entry, EntryKey, Shared, State, Watch},
+        user::Credentials,
+    };
+
+    fn paths(state: &State) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = state
+            .watches
+            .values()
+            .flatten()
+            .map(|watch| watch.data.path.clone())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    fn setup(name: &str) -> (PathBuf, Inotify, State) {
+        let dir = std::env::temp_dir().join(format!("rsincron-{name}-{}", std::process::id()));
+        fs::create_dir_all(dir.join("tree/a")).unwrap();
+        let config = Config {
+            watch_table_file: dir.join("rsincron.table"),
+            ..Config::default()
+        };
+        let mut inotify = Inotify::init().unwrap();
+        let state = State::new(&mut inotify, config);
+        (dir, inotify, state)
+    }
+
+    fn write_table(dir: &Path, lines: &[&str]) {
+        let table: String = lines
+            .iter()
+            .map(|line| format!("{} {line}\n", dir.join("tree").display()))
+            .collect();
+        fs::write(dir.join("rsincron.table"), table).unwrap();
+    }
+
+    #[test]
+    fn test_reload_watches() {
+        let (dir, _inotify, mut state) = setup("reload");
+        let tree = dir.join("tree");
+
+        write_table(&dir, &["IN_CREATE,recursive=true echo $#"]);
+        state.reload_watches();
+        assert_eq!(paths(&state), [tree.clone(), tree.join("a")]);
+
+        // Changed in place, subdirectories included
+        write_table(&dir, &["IN_CREATE,IN_MODIFY,recursive=true echo $#"]);
+        state.reload_watches();
+        assert_eq!(paths(&state), [tree.clone(), tree.join("a")]);
+        assert!(state
+            .watches
+            .values()
+            .flatten()
+            .all(|watch| watch.data.masks.contains(WatchMask::MODIFY)));
+
+        // Hidden directories walked into
+        fs::create_dir(tree.join(".git")).unwrap();
+        write_table(&dir, &["IN_CREATE,recursive=true,dotdirs=true echo $#"]);
+        state.reload_watches();
+        assert_eq!(
+            paths(&state),
+            [tree.clone(), tree.join(".git"), tree.join("a")]
+        );
+
+        write_table(&dir, &["IN_CREATE,recursive=true echo $#"]);
+        state.reload_watches();
+        assert_eq!(paths(&state), [tree.clone(), tree.join("a")]);
+
+        // Excluded directories no longer watched, and watched again
+        write_table(&dir, &["IN_CREATE,recursive=true,exclude=a/ echo $#"]);
+        state.reload_watches();
+        assert_eq!(paths(&state), std::slice::from_ref(&tree));
+
+        write_table(&dir, &["IN_CREATE,recursive=true echo $#"]);
+        state.reload_watches();
+        assert_eq!(paths(&state), [tree.clone(), tree.join("a")]);
+
+        // No longer recursive
+        write_table(&dir, &["IN_CREATE echo $#"]);
+        state.reload_watches();
+        assert_eq!(paths(&state), [tree]);
+
+        write_table(&dir, &[]);
+        state.reload_watches();
+        assert!(paths(&state).is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rename_subdirectory() {
+        let (dir, _inotify, mut state) = setup("rename");
+        let tree = dir.join("tree");
+
+        write_table(&dir, &["IN_CREATE,IN_MOVED_TO,recursive=true echo $#"]);
+        state.reload_watches();
+        let root = state
+            .watches
+            .iter()
+            .find(|(_, watches)| watches[0].data.path == tree)
+            .map(|(wd, _)| wd.clone())
+            .unwrap();
+
+        // The kernel keeps the descriptor of a renamed directory
+        fs::rename(tree.join("a"), tree.join("b")).unwrap();
+        state.add_subdirectory(&root, "b".as_ref());
+        assert_eq!(paths(&state), [tree.clone(), tree.join("b")]);
+
+        // Nor does retrying a watch added meanwhile duplicate it
+        state.failed_watches = state.watches[&root].clone();
+        state.recover_watches();
+        let descriptors: HashSet<_> = state.watches.keys().collect();
+        assert_eq!(descriptors.len(), 2);
+        assert_eq!(paths(&state), [tree.clone(), tree.join("b")]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_table_directory() {
+        let (dir, _inotify, mut state) = setup("recover");
+        state.reload_watches();
+        let wd = state.table_watches.keys().next().unwrap().clone();
+
+        // Removed along with its directory
+        fs::remove_dir_all(&dir).unwrap();
+        state.fail_watches(&wd);
+        state.recover_watches();
+        assert!(state.table_watches.is_empty());
+
+        fs::create_dir_all(dir.join("tree/a")).unwrap();
+        write_table(&dir, &["IN_CREATE echo $#"]);
+        state.recover_watches();
+        assert_eq!(state.table_watches.len(), 1);
+        assert_eq!(paths(&state), [dir.join("tree")]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_unlistable_directory() {
+        let (dir, _inotify, mut state) = setup("access");
+        let tree = dir.join("tree");
+        fs::create_dir(tree.join("secret")).unwrap();
+        fs::set_permissions(tree.join("secret"), Permissions::from_mode(0o700)).unwrap();
+        let nobody = Credentials {
+            name: String::from("nobody"),
+            uid: Uid::from_raw(65534),
+            gid: Gid::from_raw(65534),
+            groups: vec![Gid::from_raw(65534)],
+            home: PathBuf::from("/"),
+        };
+
+        for root in [tree.clone(), tree.join("secret")] {
+            let key = EntryKey {
+                table: dir.join("nobody"),
+                root: root.clone(),
+            };
+            let entry = Entry {
+                user: Some(nobody.clone()),
+                data: format!("{} IN_CREATE,recursive=true echo", root.display())
+                    .parse()
+                    .unwrap(),
+                line_number: 1,
+            };
+            state.add_root(&key, &entry);
+        }
+
+        assert_eq!(paths(&state), [tree.clone(), tree.join("a")]);
+        assert!(state.failed_watches.is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_start_run() {
+        let (dir, _inotify, state) = setup("run");
+        let shared = Arc::new(Shared {
+            state: state.into(),
+            debouncer: Debouncer::default(),
+            limiter: Limiter::new(0, 1),
+            renames: Renames::default(),
+        });
+        let watch = |line: &str| Watch {
+            key: EntryKey {
+                table: dir.join("rsincron.table"),
+                root: PathBuf::from("/var/tmp"),
+            },
+            user: None,
+            data: format!("/var/tmp {line}").parse().unwrap(),
+            line_number: 1,
+            fired: 0,
+        };
+
+        // Events the command causes while it runs are ignored
+        let (once, loopable) = (
+            watch("IN_CREATE echo"),
+            watch("IN_CREATE,loopable=true echo"),
+        );
+        let running = shared.start_run(&once).unwrap();
+        assert!(shared.is_running(&once.key));
+        assert!(shared.start_run(&once).is_none());
+        drop(running);
+        assert!(!shared.is_running(&once.key));
+        assert!(shared.start_run(&once).is_some());
+
+        let running = shared.start_run(&loopable).unwrap();
+        assert!(shared.start_run(&loopable).is_some());
+        drop(running);
+        assert!(!shared.is_running(&loopable.key));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use uuid::Uuid;
+
+/// Advisory lock serializing the writers of a watch table, held on a
+/// `<table>.lock` file next to it since the table itself gets replaced on
+/// every write. Released on drop.
+pub struct TableLock(File);
+
+impl TableLock {
+    pub fn exclusive(table: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock_path(table))?;
+        file.lock()?;
+        Ok(Self(file))
+    }
+}
+
+impl Drop for TableLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+#[derive(Debug)]
+pub enum WriteTableError {
+    Io(io::Error),
+    /// The table changed since the snapshot the new content was based on
+    Modified,
+}
+
+impl From<io::Error> for WriteTableError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+fn lock_path(table: &Path) -> PathBuf {
+    let mut path = OsString::from(table.as_os_str());
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+/// Whether a file in a table directory is a table, rather than one of the
+/// lock or temporary files written next to it
+pub fn is_table_name(name: &OsStr) -> bool {
+    let name = name.as_encoded_bytes();
+    !name.starts_with(b".") && !name.ends_with(b".lock")
+}
+
+/// Every table in `directory`, sorted by name
+pub fn list(directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut tables = Vec::new();
+
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        if is_table_name(&entry.file_name()) && entry.file_type()?.is_file() {
+            tables.push(entry.path());
+        }
+    }
+
+    tables.sort();
+    Ok(tables)
+}
+
+fn read_unlocked(table: &Path) -> io::Result<Option<String>> {
+    match fs::read_to_string(table) {
+        Ok(content) => Ok(Some(content)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Reads the table while holding a shared lock on it; `None` if it doesn't
+/// exist. Writes rename a new file over the table, so this one stays whole.
+pub fn read(table: &Path) -> io::Result<Option<String>> {
+    let mut file = match File::open(table) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+    };
+    file.lock_shared()?;
+
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    Ok(Some(content))
+}
+
+/// Atomically replaces the table with `content`, provided it still matches
+/// `snapshot` (`None` meaning the table must not exist).
+///
+/// The content goes to a temporary file in the same directory which is
+/// synced and then renamed over the table, so readers see either the old or
+/// the new table and never a truncated one.
+pub fn write(table: &Path, content: &str, snapshot: Option<&str>) -> Result<(), WriteTableError> {
+    let _lock = TableLock::exclusive(table)?;
+
+    if read_unlocked(table)?.as_deref() != snapshot {
+        return Err(WriteTableError::Modified);
+    }
+
+    let directory = match table.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut tmpfile_name = OsString::from(".");
+    tmpfile_name.push(table.file_name().unwrap_or_default());
+    tmpfile_name.push(format!(".{}.tmp", Uuid::new_v4()));
+    let tmpfile_path = directory.join(tmpfile_name);
+
+    let result = (|| {
+        let mut tmpfile = File::create(&tmpfile_path)?;
+        tmpfile.write_all(content.as_bytes())?;
+        tmpfile.sync_all()?;
+        fs::rename(&tmpfile_path, table)?;
+        // Persist the rename itself
+        File::open(directory)?.sync_all()
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmpfile_path);
+    }
+
+    Ok(result?)
+}
+
+/// Deletes the table while holding the exclusive lock
+pub fn remove(table: &Path) -> io::Result<()> {
+    let _lock = TableLock::exclusive(table)?;
+    fs::remove_file(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use uuid::Uuid;
+
+    use crate::table::{list, read, write, WriteTableError};
+
+    #[test]
+    fn test_write_table() {
+        let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir(&directory).unwrap();
+        let table = directory.join("rsincron.table");
+
+        assert_eq!(read(&table).unwrap(), None);
+        assert_eq!(
+            read(&directory.join("missing/rsincron.table")).unwrap(),
+            None
+        );
+        // Reading leaves no lock file behind
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 0);
+
+        write(&table, "first\n", None).unwrap();
+        assert_eq!(read(&table).unwrap().as_deref(), Some("first\n"));
+
+        assert!(matches!(
+            write(&table, "second\n", None),
+            Err(WriteTableError::Modified)
+        ));
+        assert!(matches!(
+            write(&table, "second\n", Some("stale\n")),
+            Err(WriteTableError::Modified)
+        ));
+        write(&table, "second\n", Some("first\n")).unwrap();
+        assert_eq!(read(&table).unwrap().as_deref(), Some("second\n"));
+
+        let mut entries: Vec<_> = fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, ["rsincron.table", "rsincron.table.lock"]);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_list_tables() {
+        let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir(&directory).unwrap();
+        write(&directory.join("bob"), "", None).unwrap();
+        write(&directory.join("alice"), "", None).unwrap();
+        fs::write(directory.join(".alice.tmp"), "").unwrap();
+        fs::create_dir(directory.join("carol")).unwrap();
+
+        assert_eq!(
+            list(&directory).unwrap(),
+            vec![directory.join("alice"), directory.join("bob")]
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,350 @@
+//! Placeholders in commands, replaced by what the event is about
+
+use std::{ffi::OsString, fmt, ops::Range, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placeholder {
+    /// `$@`, path of the watch the event came from
+    Watch,
+    /// `$#`, name of the file, empty for events on the watch itself
+    Name,
+    /// `$%`, event masks as text
+    Events,
+    /// `$&`, event masks as bits
+    Mask,
+    /// `$*`, events coalesced into this one
+    Count,
+    /// `${path}`, absolute path of the file
+    Path,
+    /// `${dir}`, directory of the file relative to the root of the watch
+    Dir,
+    /// `${base}`, last component of `${path}`
+    Base,
+    /// `${stem}`, `${base}` without its extension
+    Stem,
+    /// `${ext}`, extension of `${base}`, without the dot
+    Extension,
+    /// `${cookie}`, ties `IN_MOVED_FROM` and `IN_MOVED_TO` together
+    Cookie,
+    /// `${old}`, absolute path of the file before it moved
+    Old,
+    /// `${new}`, absolute path of the file after it moved
+    New,
+    /// `${time}`, when the event was read, in RFC 3339
+    Time,
+    /// `${host}`, name of the machine
+    Host,
+}
+
+/// Placeholders written `${name}`
+const NAMED: &[(&str, Placeholder)] = &[
+    ("path", Placeholder::Path),
+    ("dir", Placeholder::Dir),
+    ("base", Placeholder::Base),
+    ("stem", Placeholder::Stem),
+    ("ext", Placeholder::Extension),
+    ("cookie", Placeholder::Cookie),
+    ("old", Placeholder::Old),
+    ("new", Placeholder::New),
+    ("time", Placeholder::Time),
+    ("host", Placeholder::Host),
+];
+
+impl Placeholder {
+    fn from_symbol(symbol: char) -> Option<Self> {
+        match symbol {
+            '@' => Some(Self::Watch),
+            '#' => Some(Self::Name),
+            '%' => Some(Self::Events),
+            '&' => Some(Self::Mask),
+            '*' => Some(Self::Count),
+            _ => None,
+        }
+    }
+}
+
+/// The placeholder as written in tables
+impl fmt::Display for Placeholder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Watch => f.write_str("$@"),
+            Self::Name => f.write_str("$#"),
+            Self::Events => f.write_str("$%"),
+            Self::Mask => f.write_str("$&"),
+            Self::Count => f.write_str("$*"),
+            named => {
+                let (name, _) = NAMED.iter().find(|(_, p)| p == named).unwrap();
+                write!(f, "${{{name}}}")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+/// Text with placeholders, `$$` standing for `$`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateError {
+    /// Byte range of the offending `$` sequence
+    pub range: Range<usize>,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.reason)
+    }
+}
+
+impl FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let error = |range, reason| Err(TemplateError { range, reason });
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = source.char_indices().peekable();
+
+        while let Some((start, c)) = chars.next() {
+            if c != '$' {
+                text.push(c);
+                continue;
+            }
+
+            let placeholder = match chars.next() {
+                Some((_, '$')) => {
+                    text.push('$');
+                    continue;
+                }
+                Some((_, '{')) => {
+                    let Some(length) = source[start..].find('}') else {
+                        return error(start..source.len(), "unclosed `${`");
+                    };
+                    let end = start + length + 1;
+                    while chars.next_if(|&(index, _)| index < end).is_some() {}
+
+                    match NAMED
+                        .iter()
+                        .find(|(name, _)| *name == &source[start + 2..end - 1])
+                    {
+                        Some(&(_, placeholder)) => placeholder,
+                        None => return error(start..end, "unknown placeholder"),
+                    }
+                }
+                Some((index, symbol)) => match Placeholder::from_symbol(symbol) {
+                    Some(placeholder) => placeholder,
+                    None => {
+                        let end = index + symbol.len_utf8();
+                        return error(start..end, "unknown placeholder: write `$$` for a `$`");
+                    }
+                },
+                None => return error(start..source.len(), "lone `$`: write `$$` for a `$`"),
+            };
+
+            if !text.is_empty() {
+                segments.push(Segment::Text(std::mem::take(&mut text)));
+            }
+            segments.push(Segment::Placeholder(placeholder));
+        }
+
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+
+        Ok(Self {
+            source: source.to_owned(),
+            segments,
+        })
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Where the shell reads a placeholder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Context {
+    Unquoted,
+    Single,
+    Double,
+    /// Within `$(...)`, where words are unquoted again
+    Parenthesis,
+    /// Within a backquoted command, unquoted again as well
+    Backquote,
+}
+
+impl Template {
+    /// Replaces every placeholder with its `value`
+    pub fn expand(&self, value: impl Fn(Placeholder) -> OsString) -> OsString {
+        let mut expanded = OsString::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => expanded.push(text),
+                Segment::Placeholder(placeholder) => expanded.push(value(*placeholder)),
+            }
+        }
+
+        expanded
+    }
+
+    /// Same as `expand` for a shell command line, whose values are passed as
+    /// `${1}`, `${2}`... so that the shell never reads them as code
+    pub fn expand_for_shell(
+        &self,
+        value: impl Fn(Placeholder) -> OsString,
+    ) -> (String, Vec<OsString>) {
+        let mut script = String::new();
+        let mut placeholders = Vec::new();
+        let mut contexts = vec![Context::Unquoted];
+        let (mut escaped, mut dollar) = (false, false);
+
+        for segment in &self.segments {
+            let context = *contexts.last().unwrap();
+            let placeholder = match segment {
+                Segment::Text(text) => {
+                    for c in text.chars() {
+                        let context = *contexts.last().unwrap();
+                        let was_escaped = escaped;
+                        match (context, c) {
+                            _ if escaped => escaped = false,
+                            (Context::Single, '\'') => {
+                                contexts.pop();
+                            }
+                            (Context::Single, _) => (),
+                            (_, '\\') => escaped = true,
+                            (Context::Backquote, '`') => {
+                                contexts.pop();
+                            }
+                            (_, '`') => contexts.push(Context::Backquote),
+                            (_, '(') if dollar => contexts.push(Context::Parenthesis),
+                            (Context::Double, '"') => {
+                                contexts.pop();
+                            }
+                            (Context::Double, _) => (),
+                            (_, '"') => contexts.push(Context::Double),
+                            (_, '\'') => contexts.push(Context::Single),
+                            (Context::Parenthesis, '(') => contexts.push(Context::Parenthesis),
+                            (Context::Parenthesis, ')') => {
+                                contexts.pop();
+                            }
+                            _ => (),
+                        }
+                        dollar = c == '$' && !was_escaped;
+                    }
+                    script.push_str(text);
+                    continue;
+                }
+                Segment::Placeholder(placeholder) => placeholder,
+            };
+
+            // The backslash escapes the `$`, leaving the placeholder to the shell
+            if escaped {
+                escaped = false;
+                script.push_str(&placeholder.to_string());
+                continue;
+            }
+
+            let index = match placeholders.iter().position(|p| p == placeholder) {
+                Some(index) => index + 1,
+                None => {
+                    placeholders.push(*placeholder);
+                    placeholders.len()
+                }
+            };
+            script.push_str(&match context {
+                Context::Double => format!("${{{index}}}"),
+                Context::Single => format!("'\"${{{index}}}\"'"),
+                _ => format!("\"${{{index}}}\""),
+            });
+            dollar = false;
+        }
+
+        let values = placeholders.into_iter().map(value).collect();
+        (script, values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::OsString,
+        os::unix::ffi::{OsStrExt, OsStringExt},
+    };
+
+    use crate::template::{Placeholder, Template, TemplateError};
+
+    fn value(placeholder: Placeholder) -> OsString {
+        match placeholder {
+            Placeholder::Name => OsString::from_vec(b"caf\xe9".to_vec()),
+            Placeholder::Base => OsString::from(r#"it's "$x""#),
+            Placeholder::Extension => OsString::from("txt"),
+            other => OsString::from(other.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_parse_template() {
+        let error = |template: &str| template.parse::<Template>().unwrap_err();
+
+        assert_eq!(
+            error("a $x b"),
+            TemplateError {
+                range: 2..4,
+                reason: "unknown placeholder: write `$$` for a `$`"
+            }
+        );
+        assert_eq!(error("${nope} ${path}").range, 0..7);
+        assert_eq!(error("x ${path").reason, "unclosed `${`");
+        assert_eq!(error("cost: 5$").range, 7..8);
+
+        for source in ["$$HOME", "${path}${dir}", "$@/$#.${ext}", "$*$&$%", ""] {
+            assert_eq!(source.parse::<Template>().unwrap().to_string(), source);
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        let template: Template = "$$1 $#.${ext} ${host}".parse().unwrap();
+        assert_eq!(template.expand(value).as_bytes(), b"$1 caf\xe9.txt ${host}");
+    }
+
+    #[test]
+    fn test_expand_for_shell() {
+        let expand = |line: &str| line.parse::<Template>().unwrap().expand_for_shell(value);
+
+        let (script, values) = expand(r#"echo ${base} "${base}" '${base}' ${ext}"#);
+        assert_eq!(script, r#"echo "${1}" "${1}" ''"${1}"'' "${2}""#);
+        assert_eq!(values, [r#"it's "$x""#, "txt"]);
+
+        // Substituted commands are unquoted, even between double quotes
+        let (script, _) = expand(r#"echo "$$(basename $# "${ext}")" "`basename ${ext}`""#);
+        assert_eq!(
+            script,
+            r#"echo "$(basename "${1}" "${2}")" "`basename "${2}"`""#
+        );
+
+        // An escaped `$` doesn't start a substitution
+        let (script, _) = expand(r#"echo \$$(x) '$@'"#);
+        assert_eq!(script, r#"echo \$(x) ''"${1}"''"#);
+        let (script, _) = expand(r#"echo "\$$(" $@"#);
+        assert_eq!(script, r#"echo "\$(" "${1}""#);
+
+        // Escaped placeholders are left to the shell
+        let (script, values) = expand(r#"echo "\"${ext}" \${ext}"#);
+        assert_eq!(script, r#"echo "\"${1}" \${ext}"#);
+        assert_eq!(values, ["txt"]);
+    }
+}
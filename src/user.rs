@@ -0,0 +1,100 @@
+use std::{
+    ffi::CString,
+    fs::{self, Metadata},
+    io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use nix::unistd::{self, Gid, Uid, User};
+
+/// Identity the commands of a user's table run with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub name: String,
+    pub uid: Uid,
+    pub gid: Gid,
+    /// Supplementary groups, primary group included
+    pub groups: Vec<Gid>,
+    pub home: PathBuf,
+}
+
+impl Credentials {
+    fn from_user(user: User) -> io::Result<Self> {
+        let groups = unistd::getgrouplist(&CString::new(user.name.as_str())?, user.gid)?;
+
+        Ok(Self {
+            name: user.name,
+            uid: user.uid,
+            gid: user.gid,
+            groups,
+            home: user.dir,
+        })
+    }
+
+    /// `None` if there is no user called `name`
+    pub fn lookup(name: &str) -> io::Result<Option<Self>> {
+        User::from_name(name)?.map(Self::from_user).transpose()
+    }
+
+    /// The user running this process
+    pub fn current() -> io::Result<Option<Self>> {
+        User::from_uid(Uid::current())?
+            .map(Self::from_user)
+            .transpose()
+    }
+
+    /// Whether the user could list `path` themselves: search every directory
+    /// leading to it, then read it, and search it too if it is a directory
+    pub fn may_list(&self, path: &Path) -> io::Result<bool> {
+        if self.uid.is_root() {
+            return Ok(true);
+        }
+
+        for directory in path.ancestors().skip(1) {
+            if !self.is_granted(&fs::metadata(directory)?, 0o1) {
+                return Ok(false);
+            }
+        }
+
+        let metadata = fs::metadata(path)?;
+        let wanted = if metadata.is_dir() { 0o5 } else { 0o4 };
+        Ok(self.is_granted(&metadata, wanted))
+    }
+
+    /// Whether the permission bits of `metadata` grant the user `wanted`,
+    /// `rwx` being `0o7`
+    fn is_granted(&self, metadata: &Metadata, wanted: u32) -> bool {
+        let shift = if metadata.uid() == self.uid.as_raw() {
+            6
+        } else if self.groups.iter().any(|gid| gid.as_raw() == metadata.gid()) {
+            3
+        } else {
+            0
+        };
+
+        (metadata.mode() >> shift) & wanted == wanted
+    }
+
+    /// Makes `command` run as this user, with its groups and environment
+    pub fn apply(&self, command: &mut tokio::process::Command) {
+        command
+            .env("HOME", &self.home)
+            .env("USER", &self.name)
+            .env("LOGNAME", &self.name);
+
+        let (uid, gid, groups) = (self.uid, self.gid, self.groups.clone());
+        // SAFETY: the closure runs between fork and exec, where only
+        // async-signal-safe functions may be called: these are plain syscalls.
+        // std's own `uid`/`gid` can't be used as they drop privileges before
+        // `pre_exec` runs, after which groups can't be changed anymore.
+        unsafe {
+            command.pre_exec(move || {
+                unistd::setgroups(&groups)?;
+                unistd::setgid(gid)?;
+                unistd::setuid(uid)?;
+                Ok(())
+            });
+        }
+    }
+}
@@ -1,88 +1,597 @@
 use std::{
-    ffi::OsString,
-    io,
+    ffi::{OsStr, OsString},
+    fmt, io,
     path::{Path, PathBuf},
-    process::ExitStatus,
+    pin::pin,
+    process::{ExitStatus, Stdio},
     str::FromStr,
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::{
-    events::MaskWrapper,
-    parser::WatchOption,
-    parser::{parse_command, parse_masks, parse_path},
+    events::{event_names, MaskWrapper, NO_LOOP},
+    output::{Output, OutputLimits, Sink},
+    parser::{
+        parse_field, parse_option_tokens, split_command, CommandError, Field, ParseError,
+        WatchOption,
+    },
+    template::{Placeholder, Template},
+    user::Credentials,
 };
-use inotify::{Event, WatchMask};
+use glob::{Pattern, PatternError};
+use inotify::{Event, EventMask, WatchMask};
+use nix::{
+    errno::Errno,
+    sys::{
+        signal::{killpg, Signal},
+        wait::{waitid, Id, WaitPidFlag},
+    },
+    unistd::{gethostname, Pid},
+};
+use tokio::process::Child;
 use tracing::{event, Level};
-use winnow::{combinator::cut_err, Parser};
+use winnow::{stream::Offset, token::rest, Parser};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Command {
     pub program: String,
-    pub argv: Vec<String>,
+    pub argv: Vec<Template>,
+    /// The command as written in the table, run as is by the shell
+    pub line: Template,
+}
+
+/// `PATH` of commands whose environment is cleared
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Shell running commands of entries with `shell`
+const SHELL: &str = "/bin/sh";
+
+/// How long output may keep coming once the command exited
+const OUTPUT_DRAIN: Duration = Duration::from_secs(1);
+
+/// How long a command may run before its process group gets SIGTERM, then
+/// SIGKILL once `grace` passes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout {
+    pub after: Duration,
+    pub grace: Duration,
+}
+
+/// What a command runs for
+#[derive(Debug, Clone, Copy)]
+pub struct Trigger<'a> {
+    /// Path of the watch the event came from
+    pub path: &'a Path,
+    /// Path of the entry, above `path` for subdirectories of recursive watches
+    pub root: &'a Path,
+    pub event: &'a Event<OsString>,
+    /// Path of the file before it was renamed, for renames
+    pub renamed_from: Option<&'a Path>,
+    /// Events coalesced into `event`
+    pub count: u64,
+    /// When the event was read
+    pub time: SystemTime,
+    /// Line of the entry in its table
+    pub line_number: usize,
+}
+
+impl Trigger<'_> {
+    /// What `placeholder` stands for
+    pub fn value(&self, placeholder: Placeholder) -> OsString {
+        let full_path = || match self.event.name {
+            Some(ref name) => self.path.join(name),
+            None => self.path.to_owned(),
+        };
+        let base = || full_path().file_name().unwrap_or_default().to_owned();
+
+        match placeholder {
+            Placeholder::Watch => self.path.into(),
+            Placeholder::Name => self.event.name.clone().unwrap_or_default(),
+            Placeholder::Events => format!("\"{:?}\"", self.event.mask).into(),
+            Placeholder::Mask => self.event.mask.bits().to_string().into(),
+            Placeholder::Count => self.count.to_string().into(),
+            Placeholder::Path => full_path().into(),
+            Placeholder::Dir => self
+                .path
+                .strip_prefix(self.root)
+                .unwrap_or(self.path)
+                .into(),
+            Placeholder::Base => base(),
+            Placeholder::Stem => Path::new(&base()).file_stem().unwrap_or_default().into(),
+            Placeholder::Extension => Path::new(&base()).extension().unwrap_or_default().into(),
+            Placeholder::Cookie => self.event.cookie.to_string().into(),
+            Placeholder::Old => match self.renamed_from {
+                Some(path) => path.into(),
+                None if self.event.mask.contains(EventMask::MOVED_FROM) => full_path().into(),
+                None => OsString::new(),
+            },
+            Placeholder::New => match self.event.mask.contains(EventMask::MOVED_TO) {
+                true => full_path().into(),
+                false => OsString::new(),
+            },
+            Placeholder::Time => humantime::format_rfc3339_millis(self.time)
+                .to_string()
+                .into(),
+            Placeholder::Host => gethostname().unwrap_or_default(),
+        }
+    }
+
+    /// Variables describing the event, set for every command
+    pub fn environment(&self) -> Vec<(&'static str, OsString)> {
+        vec![
+            ("RSINCRON_WATCH", self.value(Placeholder::Watch)),
+            ("RSINCRON_NAME", self.value(Placeholder::Name)),
+            ("RSINCRON_PATH", self.value(Placeholder::Path)),
+            ("RSINCRON_EVENTS", event_names(self.event.mask).into()),
+            ("RSINCRON_MASK", self.value(Placeholder::Mask)),
+            ("RSINCRON_COOKIE", self.value(Placeholder::Cookie)),
+            ("RSINCRON_TIME", self.value(Placeholder::Time)),
+            ("RSINCRON_LINE", self.line_number.to_string().into()),
+            (
+                "RSINCRON_WATCH_ID",
+                self.event.wd.get_watch_descriptor_id().to_string().into(),
+            ),
+            ("RSINCRON_COUNT", self.value(Placeholder::Count)),
+        ]
+    }
+}
+
+/// How a command runs, besides what it runs for
+pub struct Execution<'a> {
+    /// User to run as, the daemon's own if `None`
+    pub user: Option<&'a Credentials>,
+    pub environment: Environment,
+    pub timeout: Option<Timeout>,
+    pub output: &'a Output,
+    pub output_limits: OutputLimits,
+    /// Whether the line goes through `/bin/sh -c` rather than being split
+    pub shell: bool,
+}
+
+/// How a command ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outcome {
+    pub status: ExitStatus,
+    pub duration: Duration,
+    /// Whether it was killed for running past its timeout
+    pub timed_out: bool,
 }
 
 impl Command {
+    /// Runs the command for `trigger` in a process group of its own
     pub async fn execute(
         &self,
-        path: &Path,
-        event: &Event<OsString>,
-    ) -> Result<ExitStatus, io::Error> {
-        tokio::process::Command::new(&self.program)
-            .args(self.argv.iter().map(|arg| {
-                let mut formatted = String::new();
-                let mut parsing_dollar = false;
-
-                for c in arg.chars() {
-                    if c == '$' {
-                        if parsing_dollar {
-                            formatted.push(c);
-                        }
-                        parsing_dollar = !parsing_dollar;
-                    } else if parsing_dollar {
-                        match c {
-                            '#' => formatted.push_str(
-                                event
-                                    .name
-                                    .as_deref()
-                                    .map(|s| s.to_str().unwrap_or_default())
-                                    .unwrap_or_default(),
-                            ),
-                            '@' => formatted.push_str(path.to_str().unwrap_or_default()),
-                            '%' => formatted.push_str(&format!("\"{:?}\"", event.mask)),
-                            '&' => formatted.push_str(&event.mask.bits().to_string()),
-                            _ => formatted.push(c),
-                        }
-                        parsing_dollar = false;
-                    } else {
-                        formatted.push(c);
-                    }
-                }
-                formatted
-            }))
-            .status()
-            .await
+        trigger: &Trigger<'_>,
+        execution: &Execution<'_>,
+    ) -> Result<Outcome, io::Error> {
+        let Trigger { path, event, .. } = *trigger;
+
+        let mut command = match execution.shell {
+            true => {
+                let (script, values) = self.line.expand_for_shell(|p| trigger.value(p));
+                let mut command = tokio::process::Command::new(SHELL);
+                command.arg("-c").arg(script).arg("sh").args(values);
+                command
+            }
+            false => {
+                let mut command = tokio::process::Command::new(&self.program);
+                command.args(self.argv.iter().map(|arg| arg.expand(|p| trigger.value(p))));
+                command
+            }
+        };
+        if execution.environment == Environment::Clear {
+            command.env_clear().env("PATH", DEFAULT_PATH);
+        }
+        command.envs(trigger.environment());
+        if let Some(user) = execution.user {
+            user.apply(&mut command);
+        }
+
+        let sink = Sink::new(
+            execution.output,
+            execution.output_limits,
+            path,
+            event,
+            execution.user,
+        );
+        let stdio = || match sink.discards() {
+            true => Stdio::null(),
+            false => Stdio::piped(),
+        };
+
+        let started = Instant::now();
+        let mut child = command
+            .process_group(0)
+            .stdin(Stdio::null())
+            .stdout(stdio())
+            .stderr(stdio())
+            .spawn()?;
+
+        let mut capture = pin!(sink.capture(child.stdout.take(), child.stderr.take()));
+        let mut wait = pin!(wait(child, execution.timeout, started));
+        let mut capturing = true;
+
+        let outcome = loop {
+            tokio::select! {
+                outcome = &mut wait => break outcome,
+                () = &mut capture, if capturing => capturing = false,
+            }
+        };
+
+        // Processes left in the background may keep the pipes open for ever
+        if capturing && tokio::time::timeout(OUTPUT_DRAIN, capture).await.is_err() {
+            event!(
+                Level::DEBUG,
+                ?path,
+                "command left processes behind: output dropped"
+            );
+        }
+
+        outcome
     }
 }
 
+/// Waits for `child` to exit, then terminates what it left in its process
+/// group; past `timeout` the whole group is killed
+async fn wait(
+    mut child: Child,
+    timeout: Option<Timeout>,
+    started: Instant,
+) -> Result<Outcome, io::Error> {
+    let (Some(timeout), Some(pid)) = (timeout, child.id()) else {
+        return Ok(Outcome {
+            status: child.wait().await?,
+            duration: started.elapsed(),
+            timed_out: false,
+        });
+    };
+
+    // The leader stays a zombie until reaped below, so the group can't be
+    // reused by another process while it gets signalled
+    let group = Pid::from_raw(pid as i32);
+    let kill = |signal| {
+        let _ = killpg(group, signal);
+    };
+    let mut exited = pin!(exited(group));
+
+    let timed_out = match tokio::time::timeout(timeout.after, &mut exited).await {
+        Ok(result) => {
+            result?;
+            false
+        }
+        Err(_) => {
+            kill(Signal::SIGTERM);
+            if tokio::time::timeout(timeout.grace, &mut exited)
+                .await
+                .is_err()
+            {
+                kill(Signal::SIGKILL);
+                exited.await?;
+            }
+            true
+        }
+    };
+    // Whatever the command left behind, or ignored SIGTERM while its leader exited
+    kill(match timed_out {
+        true => Signal::SIGKILL,
+        false => Signal::SIGTERM,
+    });
+
+    Ok(Outcome {
+        status: child.wait().await?,
+        duration: started.elapsed(),
+        timed_out,
+    })
+}
+
+/// Waits for the process `pid` to exit without reaping it
+async fn exited(pid: Pid) -> io::Result<()> {
+    let flags = WaitPidFlag::WEXITED | WaitPidFlag::WNOWAIT;
+    tokio::task::spawn_blocking(move || loop {
+        match waitid(Id::Pid(pid), flags) {
+            Err(Errno::EINTR) => continue,
+            result => return result.map(drop).map_err(io::Error::from),
+        }
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum ParseWatchError {
-    InvalidMask,
     IsComment,
-    CorruptInput,
+    Invalid(ParseError),
 }
 
+/// Glob pattern matched against file names; a trailing `/` restricts it to
+/// directories
 #[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob {
+    pattern: Pattern,
+    directories: bool,
+}
+
+impl Glob {
+    pub fn matches(&self, name: &OsStr, is_dir: bool) -> bool {
+        (is_dir || !self.directories) && self.pattern.matches(&name.to_string_lossy())
+    }
+}
+
+impl FromStr for Glob {
+    type Err = PatternError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, directories) = match s.strip_suffix('/') {
+            Some(pattern) => (pattern, true),
+            None => (s, false),
+        };
+
+        Ok(Self {
+            pattern: Pattern::new(pattern)?,
+            directories,
+        })
+    }
+}
+
+impl fmt::Display for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.pattern.as_str())?;
+        if self.directories {
+            f.write_str("/")?;
+        }
+        Ok(())
+    }
+}
+
+/// Environment commands start with, `RSINCRON_*` variables aside
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Environment {
+    /// The daemon's own
+    #[default]
+    Inherit,
+    /// Nothing but a default `PATH`, and the user's `HOME`, `USER` and `LOGNAME`
+    /// when running as another user
+    Clear,
+}
+
+impl FromStr for Environment {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inherit" => Ok(Self::Inherit),
+            "clear" => Ok(Self::Clear),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Inherit => "inherit",
+            Self::Clear => "clear",
+        })
+    }
+}
+
+/// What happens to events coming while an entry runs as many commands as its
+/// `concurrency` allows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wait for a command to exit, dropping new events once the queue is full
+    #[default]
+    Queue,
+    /// Wait as well, dropping the oldest waiting event once the queue is full
+    DropOldest,
+    /// Drop the event straight away
+    DropNewest,
+    /// Replace the waiting event, if any, adding up their counts
+    Collapse,
+}
+
+impl FromStr for Overflow {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(Self::Queue),
+            "drop-oldest" => Ok(Self::DropOldest),
+            "drop-newest" => Ok(Self::DropNewest),
+            "collapse" => Ok(Self::Collapse),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Queue => "queue",
+            Self::DropOldest => "drop-oldest",
+            Self::DropNewest => "drop-newest",
+            Self::Collapse => "collapse",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct WatchDataAttributes {
-    pub starting: bool,
     pub recursive: bool,
+    /// Whether recursive watches include hidden directories and files
+    pub dotdirs: bool,
+    /// Whether events keep running the command while it is running already,
+    /// letting a command writing into its own watch retrigger itself
+    pub loopable: bool,
+    /// If any, only events on matching names run the command
+    pub include: Vec<Glob>,
+    /// Events on matching names are ignored, matching directories not walked
+    pub exclude: Vec<Glob>,
+    /// Events closer together than this run the command once, for the last
+    pub debounce: Option<Duration>,
+    /// Whether events on different files debounce separately
+    pub debounce_per_file: bool,
+    /// How many commands of the entry may run at once, no limit but the
+    /// global one if `None`
+    pub concurrency: Option<usize>,
+    pub overflow: Overflow,
+    /// Overrides `command_timeout` from the configuration, zero disabling it
+    pub timeout: Option<Duration>,
+    /// Overrides `output` from the configuration
+    pub output: Option<Output>,
+    pub environment: Environment,
+    /// Overrides `shell` from the configuration
+    pub shell: Option<bool>,
 }
 
-impl Default for WatchDataAttributes {
-    fn default() -> Self {
-        Self {
-            starting: true,
-            recursive: false,
+/// Attributes differing from their default, written the same way as in tables
+impl fmt::Display for WatchDataAttributes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let default = Self::default();
+        let mut attributes = Vec::new();
+
+        if self.recursive != default.recursive {
+            attributes.push(format!("recursive={}", self.recursive));
         }
+
+        if self.dotdirs != default.dotdirs {
+            attributes.push(format!("dotdirs={}", self.dotdirs));
+        }
+
+        if self.loopable != default.loopable {
+            attributes.push(format!("loopable={}", self.loopable));
+        }
+
+        attributes.extend(self.include.iter().map(|glob| format!("include={glob}")));
+        attributes.extend(self.exclude.iter().map(|glob| format!("exclude={glob}")));
+
+        // In milliseconds: humantime would write spaces between units
+        if let Some(debounce) = self.debounce {
+            attributes.push(format!("debounce={}ms", debounce.as_millis()));
+        }
+
+        if self.debounce_per_file != default.debounce_per_file {
+            attributes.push(format!("debounce_per_file={}", self.debounce_per_file));
+        }
+
+        if let Some(concurrency) = self.concurrency {
+            attributes.push(format!("concurrency={concurrency}"));
+        }
+
+        if self.overflow != default.overflow {
+            attributes.push(format!("overflow={}", self.overflow));
+        }
+
+        if let Some(timeout) = self.timeout {
+            attributes.push(format!("timeout={}ms", timeout.as_millis()));
+        }
+
+        if let Some(ref output) = self.output {
+            attributes.push(format!("output={output}"));
+        }
+
+        if self.environment != default.environment {
+            attributes.push(format!("environment={}", self.environment));
+        }
+
+        if let Some(shell) = self.shell {
+            attributes.push(format!("shell={shell}"));
+        }
+
+        f.write_str(&attributes.join(","))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AttributeError {
+    Unknown,
+    /// Carries what was expected instead
+    InvalidValue(String),
+}
+
+impl WatchDataAttributes {
+    /// Sets the attribute called `name`, or adds to it for lists
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), AttributeError> {
+        let flag = || {
+            value.parse::<bool>().map_err(|_| {
+                AttributeError::InvalidValue(format!("expected `{name}=true` or `{name}=false`"))
+            })
+        };
+        let duration = || {
+            humantime::parse_duration(value).map_err(|_| {
+                AttributeError::InvalidValue(format!(
+                    "expected a duration such as `{name}=500ms` or `{name}=2s`"
+                ))
+            })
+        };
+        let glob = || match value.parse::<Glob>() {
+            Ok(glob) if !value.is_empty() => Ok(glob),
+            Ok(_) => Err(AttributeError::InvalidValue(format!(
+                "expected `{name}=<glob pattern>`"
+            ))),
+            Err(error) => Err(AttributeError::InvalidValue(format!(
+                "invalid glob pattern: {}",
+                error.msg
+            ))),
+        };
+
+        match name {
+            "recursive" => self.recursive = flag()?,
+            "dotdirs" => self.dotdirs = flag()?,
+            "loopable" => self.loopable = flag()?,
+            "include" => self.include.push(glob()?),
+            "exclude" => self.exclude.push(glob()?),
+            "debounce" => self.debounce = Some(duration()?).filter(|window| !window.is_zero()),
+            "debounce_per_file" => self.debounce_per_file = flag()?,
+            "timeout" => self.timeout = Some(duration()?),
+            "shell" => self.shell = Some(flag()?),
+            "output" => match value.parse() {
+                Ok(output) => self.output = Some(output),
+                Err(error) => return Err(AttributeError::InvalidValue(error.to_string())),
+            },
+            "environment" => {
+                self.environment = value.parse().map_err(|()| {
+                    AttributeError::InvalidValue(format!(
+                        "expected `{name}=inherit` or `{name}=clear`"
+                    ))
+                })?
+            }
+            "concurrency" => match value.parse::<usize>() {
+                Ok(limit) if limit > 0 => self.concurrency = Some(limit),
+                _ => {
+                    return Err(AttributeError::InvalidValue(format!(
+                        "expected `{name}=<number of commands>`, at least 1"
+                    )))
+                }
+            },
+            "overflow" => {
+                self.overflow = value.parse().map_err(|()| {
+                    AttributeError::InvalidValue(format!(
+                    "expected `{name}=` one of `queue`, `drop-oldest`, `drop-newest` or `collapse`"
+                ))
+                })?
+            }
+            _ => return Err(AttributeError::Unknown),
+        }
+
+        Ok(())
+    }
+
+    fn hides(&self, name: &OsStr) -> bool {
+        self.recursive && !self.dotdirs && name.as_encoded_bytes().starts_with(b".")
+    }
+
+    /// Whether the subdirectory `name` gets watched by a recursive watch
+    pub fn walks_into(&self, name: &OsStr) -> bool {
+        !self.hides(name) && !self.exclude.iter().any(|glob| glob.matches(name, true))
+    }
+
+    /// Whether an event on `name` runs the command
+    pub fn accepts(&self, name: &OsStr, is_dir: bool) -> bool {
+        !self.hides(name)
+            && !self.exclude.iter().any(|glob| glob.matches(name, is_dir))
+            && (self.include.is_empty()
+                || self.include.iter().any(|glob| glob.matches(name, is_dir)))
     }
 }
 
@@ -98,63 +607,142 @@ impl FromStr for WatchData {
     type Err = ParseWatchError;
 
     #[tracing::instrument]
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let s = s.trim();
-        if s.starts_with('#') {
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let s = line.trim();
+        if s.is_empty() || s.starts_with('#') {
             return Err(ParseWatchError::IsComment);
         };
 
-        (
-            cut_err(parse_path),
-            cut_err(parse_masks),
-            cut_err(parse_command),
-        )
-            .map(|(path, watch_options, command)| {
-                let mut masks = WatchMask::empty();
-                let mut attributes = WatchDataAttributes::default();
-
-                for option in watch_options {
-                    match option {
-                        WatchOption::Mask(mask) => {
-                            let mask = match mask.parse::<MaskWrapper>() {
-                                Ok(m) => m,
-                                Err(_) => {
-                                    event!(Level::ERROR, mask, "invalid mask");
-                                    return Err(ParseWatchError::InvalidMask);
-                                }
-                            };
-
-                            masks = masks.union(mask.0);
-                        }
-                        WatchOption::Attribute(flag, value) => match flag.as_str() {
-                            "recursive" => attributes.recursive = value,
-                            _ => continue,
-                        },
-                    }
-                }
+        let invalid = |token: &str, field: Field, reason: &str| {
+            ParseWatchError::Invalid(ParseError::new(line, token, field, reason))
+        };
 
-                Ok(WatchData {
-                    path,
-                    command,
-                    masks,
-                    attributes,
-                })
-            })
+        // None of these can fail: every field is validated separately below so
+        // that errors can point at the offending token
+        let (path, options, command) = (parse_field, parse_option_tokens, rest)
             .parse(s)
-            .map_err(|error| {
-                event!(Level::ERROR, ?error);
-                ParseWatchError::CorruptInput
-            })?
+            .map_err(|_| invalid(s, Field::Path, "malformed line"))?;
+
+        if !Path::new(path).is_absolute() {
+            return Err(invalid(path, Field::Path, "path must be absolute"));
+        }
+
+        let mut masks = WatchMask::empty();
+        let mut attributes = WatchDataAttributes::default();
+        // First of the attributes limiting commands already running
+        let mut limit = None;
+
+        for &token in &options {
+            let Ok(option) = token.parse::<WatchOption>();
+            match option {
+                WatchOption::Mask(mask) if mask == NO_LOOP => attributes.loopable = false,
+                WatchOption::Mask(mask) => match mask.parse::<MaskWrapper>() {
+                    Ok(mask) => masks = masks.union(mask.0),
+                    Err(_) => return Err(invalid(token, Field::Mask, "unknown mask")),
+                },
+                WatchOption::Attribute(name, value) => match attributes.set(&name, &value) {
+                    Ok(()) if name == "concurrency" || name == "overflow" => {
+                        limit = limit.or(Some(token))
+                    }
+                    // Unknown attributes are ignored here: `rsincrontab check` warns about them
+                    Ok(()) | Err(AttributeError::Unknown) => (),
+                    Err(AttributeError::InvalidValue(reason)) => {
+                        return Err(invalid(token, Field::Attribute, &reason))
+                    }
+                },
+            }
+        }
+
+        // Other entries never run a command while one is running already
+        if let Some(token) = limit.filter(|_| !attributes.loopable && attributes.debounce.is_none())
+        {
+            return Err(invalid(
+                token,
+                Field::Attribute,
+                "only applies to loopable or debounced watches",
+            ));
+        }
+
+        // inotify refuses to watch flags alone
+        if !masks.intersects(WatchMask::ALL_EVENTS) {
+            let (first, last) = (options[0], options[options.len() - 1]);
+            let options = &s[first.offset_from(&s)..last.offset_from(&s) + last.len()];
+            return Err(invalid(options, Field::Mask, "no event to watch"));
+        }
+
+        let command = match split_command(command) {
+            Ok(command) => command,
+            Err(CommandError::Empty) => {
+                return Err(invalid(&s[s.len()..], Field::Command, "nothing to execute"))
+            }
+            Err(CommandError::UnbalancedQuotes) => {
+                return Err(invalid(command, Field::Command, "unbalanced quotes"))
+            }
+            Err(CommandError::Template(error)) => {
+                let token = &command.trim()[error.range];
+                return Err(invalid(token, Field::Command, error.reason));
+            }
+        };
+
+        Ok(WatchData {
+            path: PathBuf::from(path),
+            command,
+            masks,
+            attributes,
+        })
     }
 }
 
+/// The entry as a table line, parsing back to the same `WatchData`
+impl fmt::Display for WatchData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.path.display(), MaskWrapper(self.masks))?;
+
+        let attributes = self.attributes.to_string();
+        if !attributes.is_empty() {
+            write!(f, ",{attributes}")?;
+        }
+
+        write!(f, " {}", self.command.line)
+    }
+}
+
+/// Parses every line of a watch table, tagging errors with their line number
+pub fn parse_table(
+    table: &str,
+) -> impl Iterator<Item = (&str, Result<WatchData, ParseWatchError>)> {
+    table.lines().enumerate().map(|(index, line)| {
+        let watch = WatchData::from_str(line).map_err(|error| match error {
+            ParseWatchError::Invalid(error) => ParseWatchError::Invalid(error.at_line(index + 1)),
+            error => error,
+        });
+
+        (line, watch)
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
+    use std::{
+        ffi::{OsStr, OsString},
+        fs,
+        os::unix::process::ExitStatusExt,
+        path::{Path, PathBuf},
+        time::{Duration, SystemTime},
+    };
 
-    use inotify::WatchMask;
+    use inotify::{Event, EventMask, Inotify, WatchMask};
+    use nix::sys::signal::Signal;
+    use tracing::Level;
 
-    use crate::watch::{Command, ParseWatchError, WatchData, WatchDataAttributes};
+    use crate::{
+        output::{Output, OutputLimits},
+        parser::{split_command, Field, ParseError},
+        watch::{
+            parse_table, Command, Execution, Outcome, Overflow, ParseWatchError, Timeout, Trigger,
+            WatchData, WatchDataAttributes,
+        },
+    };
 
     const LINE_DATA: &str = include_str!("../assets/test/test-line");
     const DATA: &str = include_str!("../assets/test/test-table");
@@ -164,12 +752,15 @@ mod tests {
             path: PathBuf::from("/var/tmp"),
             masks: WatchMask::CREATE | WatchMask::DELETE,
             attributes: WatchDataAttributes {
-                starting: true,
                 recursive: true,
+                ..WatchDataAttributes::default()
             },
             command: Command {
                 program: String::from("echo"),
-                argv: ["$@", "$#", "&>", "/dev/null"].map(String::from).to_vec(),
+                argv: ["$@", "$#", "&>", "/dev/null"]
+                    .map(|arg| arg.parse().unwrap())
+                    .to_vec(),
+                line: "echo $@ $# &> /dev/null".parse().unwrap(),
             },
         }
     }
@@ -181,17 +772,361 @@ mod tests {
 
     #[test]
     fn test_parse_table() {
+        let lines: Vec<&str> = DATA.lines().collect();
+
         assert_eq!(
-            DATA.lines()
-                .map(|l| l.parse::<WatchData>())
+            parse_table(DATA)
+                .map(|(_, watch)| watch)
                 .collect::<Vec<Result<WatchData, ParseWatchError>>>(),
             vec![
                 Ok(get_test_watch()),
                 Ok(get_test_watch()),
-                Err(ParseWatchError::InvalidMask),
+                Err(ParseWatchError::Invalid(
+                    ParseError::new(lines[2], &lines[2][20..28], Field::Mask, "unknown mask")
+                        .at_line(3)
+                )),
                 Err(ParseWatchError::IsComment),
-                Err(ParseWatchError::CorruptInput),
+                Err(ParseWatchError::Invalid(
+                    ParseError::new(lines[4], &lines[4][42..46], Field::Mask, "unknown mask")
+                        .at_line(5)
+                )),
             ]
         )
     }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |line: &str| match line.parse::<WatchData>() {
+            Err(ParseWatchError::Invalid(error)) => (error.field, error.token),
+            other => panic!("expected a parse error, got {other:?}"),
+        };
+
+        assert_eq!(
+            error("var/tmp IN_CREATE echo"),
+            (Field::Path, String::from("var/tmp"))
+        );
+        assert_eq!(
+            error("/var/tmp IN_CREATE,recursive=yes echo"),
+            (Field::Attribute, String::from("recursive=yes"))
+        );
+        assert_eq!(
+            error("/var/tmp IN_CREATE,loopable=maybe echo"),
+            (Field::Attribute, String::from("loopable=maybe"))
+        );
+        assert_eq!(
+            error("/var/tmp IN_CREATE,exclude=[a echo"),
+            (Field::Attribute, String::from("exclude=[a"))
+        );
+        assert_eq!(
+            error("/var/tmp IN_CREATE,debounce=soon echo"),
+            (Field::Attribute, String::from("debounce=soon"))
+        );
+        assert_eq!(
+            error("/var/tmp IN_CREATE,concurrency=0 echo"),
+            (Field::Attribute, String::from("concurrency=0"))
+        );
+        assert_eq!(
+            error("/var/tmp IN_CREATE,overflow=spill echo"),
+            (Field::Attribute, String::from("overflow=spill"))
+        );
+        assert_eq!(
+            error("/var/tmp IN_CREATE,overflow=collapse,concurrency=2 echo"),
+            (Field::Attribute, String::from("overflow=collapse"))
+        );
+        assert_eq!(
+            error("/var/tmp IN_CREATE echo $HOME"),
+            (Field::Command, String::from("$H"))
+        );
+        assert_eq!(
+            error("/var/tmp IN_CREATE echo ${name}"),
+            (Field::Command, String::from("${name}"))
+        );
+        assert_eq!(error("/var/tmp 0 echo"), (Field::Mask, String::from("0")));
+        assert_eq!(
+            error("/var/tmp IN_ONLYDIR,recursive=true echo"),
+            (Field::Mask, String::from("IN_ONLYDIR,recursive=true"))
+        );
+        assert_eq!(error("/var/tmp IN_CREATE"), (Field::Command, String::new()));
+        assert_eq!(
+            error("/var/tmp IN_CREATE echo 'oops"),
+            (Field::Command, String::from("echo 'oops"))
+        );
+        assert_eq!("   ".parse::<WatchData>(), Err(ParseWatchError::IsComment));
+    }
+
+    #[test]
+    fn test_no_loop() {
+        let watch = "/var/tmp IN_CREATE,loopable=true,IN_NO_LOOP echo"
+            .parse::<WatchData>()
+            .unwrap();
+        assert!(!watch.attributes.loopable);
+        assert_eq!(watch.masks, WatchMask::CREATE);
+    }
+
+    #[test]
+    fn test_dotdirs() {
+        let watch = "/var/tmp IN_CREATE,recursive=true,dotdirs=true echo"
+            .parse::<WatchData>()
+            .unwrap();
+        assert!(watch.attributes.dotdirs);
+        assert!(watch.attributes.walks_into(OsStr::new(".git")));
+
+        let watch = "/var/tmp IN_CREATE,recursive=true echo"
+            .parse::<WatchData>()
+            .unwrap();
+        assert!(!watch.attributes.walks_into(OsStr::new(".git")));
+        assert!(!watch.attributes.accepts(OsStr::new(".hidden"), false));
+        assert!(watch.attributes.walks_into(OsStr::new("src")));
+
+        // Only recursive watches leave hidden files out
+        let watch = "/var/tmp IN_CREATE echo".parse::<WatchData>().unwrap();
+        assert!(watch.attributes.accepts(OsStr::new(".hidden"), false));
+    }
+
+    #[test]
+    fn test_globs() {
+        let watch = "/var/tmp IN_CREATE,recursive=true,exclude=*.swp,exclude=target/,include=*.csv,include=*.tsv echo"
+            .parse::<WatchData>()
+            .unwrap();
+        let attributes = &watch.attributes;
+
+        assert!(attributes.accepts(OsStr::new("data.csv"), false));
+        assert!(!attributes.accepts(OsStr::new("data.json"), false));
+        assert!(!attributes.accepts(OsStr::new("data.csv.swp"), false));
+        assert!(!attributes.walks_into(OsStr::new("target")));
+        assert!(attributes.walks_into(OsStr::new("src")));
+        // `target/` only excludes directories
+        assert!(!attributes.accepts(OsStr::new("target"), true));
+        assert!(!attributes.walks_into(OsStr::new("x.swp")));
+
+        assert_eq!(
+            attributes.to_string(),
+            "recursive=true,include=*.csv,include=*.tsv,exclude=*.swp,exclude=target/"
+        );
+        assert_eq!(watch.to_string().parse::<WatchData>().unwrap(), watch);
+    }
+
+    #[test]
+    fn test_run_attributes() {
+        let watch = "/var/tmp IN_MODIFY,debounce=1s500ms,debounce_per_file=true echo $*"
+            .parse::<WatchData>()
+            .unwrap();
+        assert_eq!(watch.attributes.debounce, Some(Duration::from_millis(1500)));
+        assert_eq!(
+            watch.attributes.to_string(),
+            "debounce=1500ms,debounce_per_file=true"
+        );
+        assert_eq!(watch.to_string().parse::<WatchData>().unwrap(), watch);
+
+        let watch =
+            "/var/tmp IN_MODIFY,loopable=true,concurrency=4,overflow=drop-oldest,timeout=30s,output=log:debug,shell=true echo"
+                .parse::<WatchData>()
+                .unwrap();
+        assert_eq!(watch.attributes.concurrency, Some(4));
+        assert_eq!(watch.attributes.overflow, Overflow::DropOldest);
+        assert_eq!(watch.attributes.timeout, Some(Duration::from_secs(30)));
+        assert_eq!(watch.attributes.output, Some(Output::Log(Level::DEBUG)));
+        assert_eq!(watch.attributes.shell, Some(true));
+        assert_eq!(watch.to_string().parse::<WatchData>().unwrap(), watch);
+
+        // A zero window doesn't debounce
+        let watch = "/var/tmp IN_MODIFY,debounce=0s echo"
+            .parse::<WatchData>()
+            .unwrap();
+        assert_eq!(watch.attributes.debounce, None);
+    }
+
+    /// Runs the command of `line` for a dummy event on `name`
+    async fn execute(line: &str, name: &str, timeout: Option<Timeout>, output: &Output) -> Outcome {
+        let inotify = Inotify::init().unwrap();
+        let event = Event {
+            wd: inotify
+                .watches()
+                .add(std::env::temp_dir(), WatchMask::CREATE)
+                .unwrap(),
+            mask: EventMask::CREATE,
+            cookie: 0,
+            name: Some(OsString::from(name)),
+        };
+        let watch = line.parse::<WatchData>().unwrap();
+        let trigger = Trigger {
+            path: &watch.path,
+            // As if it were a subdirectory of a recursive watch on `/var`
+            root: Path::new("/var"),
+            event: &event,
+            renamed_from: None,
+            count: 1,
+            time: SystemTime::UNIX_EPOCH,
+            line_number: 7,
+        };
+        let execution = Execution {
+            user: None,
+            environment: watch.attributes.environment,
+            timeout,
+            output,
+            output_limits: OutputLimits {
+                per_run: 1024,
+                file_size: 1024,
+                rotations: 1,
+            },
+            shell: watch.attributes.shell.unwrap_or_default(),
+        };
+
+        watch.command.execute(&trigger, &execution).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_execute_timeout() {
+        let timeout = Some(Timeout {
+            after: Duration::from_millis(200),
+            grace: Duration::from_millis(200),
+        });
+
+        let outcome = execute(
+            "/var/tmp IN_CREATE sh -c 'exit 3'",
+            "name",
+            timeout,
+            &Output::Discard,
+        )
+        .await;
+        assert_eq!((outcome.status.code(), outcome.timed_out), (Some(3), false));
+
+        // Whatever it left in the background goes along with it
+        let file = std::env::temp_dir().join(format!("rsincron-background-{}", std::process::id()));
+        execute(
+            &format!(
+                "/var/tmp IN_CREATE sh -c 'sleep 10 & echo $$$$! > {}'",
+                file.display()
+            ),
+            "name",
+            timeout,
+            &Output::Discard,
+        )
+        .await;
+        let pid = std::fs::read_to_string(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let status = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim()));
+        assert!(status.map_or(true, |s| s.contains(") Z ")));
+
+        // Ignoring SIGTERM, inherited by `sleep`, only delays the SIGKILL
+        let outcome = execute(
+            "/var/tmp IN_CREATE sh -c 'trap \"\" TERM; sleep 10'",
+            "name",
+            timeout,
+            &Output::Discard,
+        )
+        .await;
+        assert!(outcome.timed_out);
+        assert_eq!(outcome.status.signal(), Some(Signal::SIGKILL as i32));
+        assert!(outcome.duration < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_execute_output() {
+        let file = std::env::temp_dir().join(format!("rsincron-output-{}", std::process::id()));
+        let output = Output::File(file.clone());
+
+        // Past 1024 bytes, lines are dropped
+        execute(
+            "/var/tmp IN_CREATE sh -c 'echo $#; echo oops >&2; sleep 0.1; printf %02000d 0'",
+            "name",
+            None,
+            &output,
+        )
+        .await;
+        // Both streams are read as they come
+        let mut lines: Vec<String> = fs::read_to_string(&file)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        assert_eq!(lines, vec!["name", "oops"]);
+        fs::remove_file(&file).unwrap();
+
+        execute(
+            "/var/tmp IN_CREATE,environment=clear sh -c 'env | sort'",
+            "name",
+            None,
+            &output,
+        )
+        .await;
+        let environment = fs::read_to_string(&file).unwrap();
+        let variables: Vec<&str> = environment
+            .lines()
+            .filter(|line| !line.starts_with("RSINCRON_WATCH_ID=") && !line.starts_with("PWD="))
+            .collect();
+        assert_eq!(
+            variables,
+            vec![
+                "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+                "RSINCRON_COOKIE=0",
+                "RSINCRON_COUNT=1",
+                "RSINCRON_EVENTS=IN_CREATE",
+                "RSINCRON_LINE=7",
+                "RSINCRON_MASK=256",
+                "RSINCRON_NAME=name",
+                "RSINCRON_PATH=/var/tmp/name",
+                "RSINCRON_TIME=1970-01-01T00:00:00.000Z",
+                "RSINCRON_WATCH=/var/tmp",
+            ]
+        );
+        fs::remove_file(&file).unwrap();
+
+        execute(
+            "/var/tmp IN_CREATE echo ${path} ${dir} ${base} ${stem} ${ext} ${cookie} ${time}",
+            "a.tar.gz",
+            None,
+            &output,
+        )
+        .await;
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "/var/tmp/a.tar.gz tmp a.tar.gz a.tar gz 0 1970-01-01T00:00:00.000Z\n"
+        );
+        fs::remove_file(file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_execute_shell() {
+        let file = std::env::temp_dir().join(format!("rsincron-shell-{}", std::process::id()));
+        let output = Output::File(file.clone());
+        let name = r#"it's "$(touch pwned)"; `touch pwned` \"#;
+
+        // Names stay plain text wherever they sit in the line, substituted
+        // commands included
+        execute(
+            r#"/var/tmp IN_CREATE,shell=true echo $#; echo "$#"; echo '$#' && echo "$$RSINCRON_NAME"; echo "$$(printf %s $#)"; echo "`printf %s $#`""#,
+            name,
+            None,
+            &output,
+        )
+        .await;
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            format!("{name}\n").repeat(6)
+        );
+        assert!(!PathBuf::from("pwned").exists());
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn test_display_watch() {
+        let mut watch = get_test_watch();
+        assert_eq!(
+            watch.to_string(),
+            "/var/tmp IN_CREATE,IN_DELETE,recursive=true echo $@ $# &> /dev/null"
+        );
+
+        watch.command = split_command(r##"echo it\'s '' "#1" 'a  b'"##).unwrap();
+        let argv: Vec<String> = watch
+            .command
+            .argv
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        assert_eq!(argv, ["it's", "", "#1", "a  b"]);
+        assert_eq!(watch.to_string().parse::<WatchData>().unwrap(), watch);
+    }
 }